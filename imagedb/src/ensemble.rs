@@ -0,0 +1,99 @@
+use crate::hasher::*;
+use cv::*;
+
+/// How votes of ensemble members are combined into a single decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CombinationRule {
+    /// Every hasher has to consider images similar
+    AllAgree,
+    /// More than half of hashers have to consider images similar
+    Majority,
+    /// Weighted mean of distances, each scaled by its hasher threshold, has to be below one
+    WeightedSum,
+}
+
+/// Scores an image with several hashers at once and combines their opinions.
+///
+/// Each member distance is divided by member's default threshold, so a value below `1.0` means "similar" for every
+/// algorithm. Combined distance keeps this meaning, hence default threshold of the ensemble is `1.0`.
+pub struct EnsembleHasher {
+    members: Vec<(Box<dyn PerceptualHasher>, f64)>,
+    rule: CombinationRule,
+}
+
+impl EnsembleHasher {
+    /// Creates ensemble from `(hasher, weight)` pairs. Weights only matter for `CombinationRule::WeightedSum`
+    pub fn new(rule: CombinationRule, members: Vec<(Box<dyn PerceptualHasher>, f64)>) -> Self {
+        assert!(!members.is_empty(), "ensemble should contain at least one hasher");
+        Self { members, rule }
+    }
+
+    pub fn rule(&self) -> CombinationRule {
+        self.rule
+    }
+
+    fn normalized_distances(&self, lhs: &[ImageHash], rhs: &[ImageHash]) -> Vec<f64> {
+        self.members
+            .iter()
+            .zip(lhs.iter().zip(rhs.iter()))
+            .map(|((hasher, _), (lhs, rhs))| hasher.compare(lhs, rhs) / hasher.default_threshold())
+            .collect()
+    }
+}
+
+impl PerceptualHasher for EnsembleHasher {
+    /// Combination rule and weights don't affect hashes, so they are not part of the name
+    fn name(&self) -> String {
+        let members = self
+            .members
+            .iter()
+            .map(|(hasher, _)| format!("{}:{}", hasher.name(), hasher.version()))
+            .collect::<Vec<_>>();
+        format!("ensemble({})", members.join(","))
+    }
+
+    /// Every member should get large enough image
+    fn working_size(&self) -> Option<i32> {
+        self.members
+            .iter()
+            .map(|(hasher, _)| hasher.working_size())
+            .try_fold(0, |size, member| Some(size.max(member?)))
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        ImageHash::Composite(self.members.iter().map(|(hasher, _)| hasher.compute(image)).collect())
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        let (lhs, rhs) = match (lhs, rhs) {
+            (ImageHash::Composite(lhs), ImageHash::Composite(rhs))
+                if lhs.len() == self.members.len() && rhs.len() == self.members.len() =>
+            {
+                (lhs, rhs)
+            }
+            _ => return std::f64::INFINITY,
+        };
+        let mut distances = self.normalized_distances(lhs, rhs);
+        match self.rule {
+            CombinationRule::AllAgree => distances.iter().cloned().fold(0.0, f64::max),
+            CombinationRule::Majority => {
+                distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Greater));
+                distances[distances.len() / 2]
+            }
+            CombinationRule::WeightedSum => {
+                let total_weight: f64 = self.members.iter().map(|(_, weight)| weight).sum();
+                let weighted: f64 = self
+                    .members
+                    .iter()
+                    .zip(distances.iter())
+                    .map(|((_, weight), distance)| weight * distance)
+                    .sum();
+                weighted / total_weight
+            }
+        }
+    }
+
+    fn default_threshold(&self) -> f64 {
+        1.0
+    }
+}
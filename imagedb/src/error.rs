@@ -0,0 +1,29 @@
+use crate::header::Rejection;
+use failure::Fail;
+use serde_json::Error as SerdeError;
+use std::io::Error as IoError;
+
+#[derive(Debug, Fail)]
+/// Errors that may happen while saving or loading images
+pub enum ImageDbError {
+    #[fail(display = "IO error: {:?}", _0)]
+    IoError(IoError),
+    #[fail(display = "Serde error: {:?}", _0)]
+    SerdeError(SerdeError),
+    #[fail(display = "Image {} could not be decoded", _0)]
+    UndecodableImage(String),
+    #[fail(display = "Image {} was rejected: {:?}", _0, _1)]
+    RejectedImage(String, Rejection),
+}
+
+impl From<IoError> for ImageDbError {
+    fn from(error: IoError) -> Self {
+        ImageDbError::IoError(error)
+    }
+}
+
+impl From<SerdeError> for ImageDbError {
+    fn from(error: SerdeError) -> Self {
+        ImageDbError::SerdeError(error)
+    }
+}
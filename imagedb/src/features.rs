@@ -0,0 +1,349 @@
+use cv::imgproc::*;
+use cv::*;
+use std::cmp::Reverse;
+
+/// Images are downscaled so the longest side is at most this size before looking for keypoints
+pub(crate) const WORKING_SIZE: i32 = 512;
+const MAX_KEYPOINTS: usize = 500;
+/// Keypoints are detected on downscaled copies of the image too, so crops shown at another scale still match.
+/// Eight levels cover scale difference up to 3.6 times, the same as ORB does
+const PYRAMID_LEVELS: i32 = 8;
+const PYRAMID_SCALE: f64 = 1.2;
+/// Minimal brightness difference between center and circle pixels of FAST corner
+const FAST_THRESHOLD: i16 = 20;
+/// Descriptor pairs are sampled within this radius, rotated pairs stay within `BORDER`
+const PATCH_RADIUS: i32 = 12;
+const BORDER: i32 = 18;
+const DESCRIPTOR_PAIRS: usize = 256;
+/// Matches with more differing bits are ignored
+const MAX_DESCRIPTOR_DISTANCE: u32 = 64;
+/// Best match should be that much closer than the second best one to be unambiguous
+const RATIO: f64 = 0.8;
+const RANSAC_ITERATIONS: usize = 500;
+/// Allowed distance between transformed and matched keypoint, relative to the longest side of the image
+const INLIER_TOLERANCE: f32 = 0.03;
+
+const CIRCLE: [(i32, i32); 16] = [
+    (0, -3),
+    (1, -3),
+    (2, -2),
+    (3, -1),
+    (3, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 3),
+    (-1, 3),
+    (-2, 2),
+    (-3, 1),
+    (-3, 0),
+    (-3, -1),
+    (-2, -2),
+    (-1, -3),
+];
+
+/// Oriented keypoints with binary descriptors from several scales, the same idea as ORB
+#[derive(Debug, Clone)]
+pub struct Features {
+    width: usize,
+    height: usize,
+    points: Vec<(f32, f32)>,
+    descriptors: Vec<[u64; 4]>,
+}
+
+impl Features {
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+/// Detects FAST corners on every pyramid level and computes rotated BRIEF descriptors for them.
+/// Keypoint positions are in pixels of the first level
+pub fn extract_features(image: &Mat) -> Features {
+    let gray = image.cvt_color(ColorConversion::BGR2GRAY);
+    let gray = downscale(&gray, f64::from(WORKING_SIZE) / f64::from(gray.rows.max(gray.cols)));
+    let (width, height) = (gray.cols as usize, gray.rows as usize);
+    // Keypoints are distributed between levels proportionally to their area
+    let total_area: f64 = (0..PYRAMID_LEVELS).map(|level| PYRAMID_SCALE.powi(-2 * level)).sum();
+    let pairs = sampling_pairs();
+    let mut points = Vec::new();
+    let mut descriptors = Vec::new();
+    for level in 0..PYRAMID_LEVELS {
+        let scale = PYRAMID_SCALE.powi(level);
+        let level_gray = downscale(&gray, 1.0 / scale);
+        let (level_width, level_height) = (level_gray.cols as usize, level_gray.rows as usize);
+        if level_width <= 2 * BORDER as usize || level_height <= 2 * BORDER as usize {
+            break;
+        }
+        let pixels = level_gray.data();
+        let limit = (MAX_KEYPOINTS as f64 * PYRAMID_SCALE.powi(-2 * level) / total_area).round() as usize;
+        let corners = detect_corners(pixels, level_width, level_height, limit);
+        let smoothed = box_blur(pixels, level_width, level_height);
+        for (x, y) in corners {
+            let angle = orientation(pixels, level_width, x, y);
+            descriptors.push(describe(&smoothed, level_width, x, y, angle, &pairs));
+            points.push(((f64::from(x) * scale) as f32, (f64::from(y) * scale) as f32));
+        }
+    }
+    Features {
+        width,
+        height,
+        points,
+        descriptors,
+    }
+}
+
+/// Returns the number of keypoint matches consistent with a single similarity transform between two images
+pub fn count_consistent_matches(query: &Features, train: &Features) -> usize {
+    let matches = match_descriptors(query, train);
+    if matches.len() < 2 {
+        return 0;
+    }
+    let tolerance = INLIER_TOLERANCE * train.width.max(train.height) as f32;
+    let mut random = Lcg::new(matches.len() as u64);
+    let mut best = 0;
+    for _ in 0..RANSAC_ITERATIONS {
+        let first = random.next_below(matches.len());
+        let second = random.next_below(matches.len());
+        if first == second {
+            continue;
+        }
+        let transform = match Similarity::from_pairs(matches[first], matches[second]) {
+            Some(transform) => transform,
+            None => continue,
+        };
+        let inliers = matches
+            .iter()
+            .filter(|&&(q, t)| distance(transform.apply(q), t) < tolerance)
+            .count();
+        best = best.max(inliers);
+    }
+    best
+}
+
+/// Resizes image by `scale` if it is less than one
+fn downscale(image: &Mat, scale: f64) -> Mat {
+    if scale >= 1.0 {
+        return image.clone();
+    }
+    let width = ((f64::from(image.cols) * scale).round() as i32).max(1);
+    let height = ((f64::from(image.rows) * scale).round() as i32).max(1);
+    image.resize_to(Size2i::new(width, height), InterpolationFlag::InterArea)
+}
+
+/// Returns up to `limit` strongest corners that are local maxima of the score
+fn detect_corners(pixels: &[u8], width: usize, height: usize, limit: usize) -> Vec<(i32, i32)> {
+    let border = BORDER as usize;
+    if width <= 2 * border || height <= 2 * border {
+        return Vec::new();
+    }
+    let mut scores = vec![0i32; width * height];
+    for y in border..height - border {
+        for x in border..width - border {
+            scores[y * width + x] = corner_score(pixels, width, x as i32, y as i32);
+        }
+    }
+    let mut corners = Vec::new();
+    for y in border..height - border {
+        for x in border..width - border {
+            let score = scores[y * width + x];
+            if score == 0 {
+                continue;
+            }
+            let is_maximum = (y - 1..=y + 1)
+                .flat_map(|ny| (x - 1..=x + 1).map(move |nx| (nx, ny)))
+                .filter(|&(nx, ny)| (nx, ny) != (x, y))
+                .all(|(nx, ny)| scores[ny * width + nx] <= score);
+            if is_maximum {
+                corners.push((score, x as i32, y as i32));
+            }
+        }
+    }
+    corners.sort_by_key(|&(score, _, _)| Reverse(score));
+    corners.truncate(limit);
+    corners.into_iter().map(|(_, x, y)| (x, y)).collect()
+}
+
+/// FAST-9 test: nine contiguous circle pixels should all be brighter or all darker than the center.
+/// Returns sum of differences above threshold for corners and zero otherwise
+fn corner_score(pixels: &[u8], width: usize, x: i32, y: i32) -> i32 {
+    let at = |dx: i32, dy: i32| i16::from(pixels[(y + dy) as usize * width + (x + dx) as usize]);
+    let center = at(0, 0);
+    let mut states = [0i8; 16];
+    let mut score = 0;
+    for (state, &(dx, dy)) in states.iter_mut().zip(CIRCLE.iter()) {
+        let difference = at(dx, dy) - center;
+        if difference > FAST_THRESHOLD {
+            *state = 1;
+        } else if difference < -FAST_THRESHOLD {
+            *state = -1;
+        }
+        score += (i32::from(difference.abs()) - i32::from(FAST_THRESHOLD)).max(0);
+    }
+    let is_corner = [1i8, -1].iter().any(|&expected| {
+        let mut run = 0;
+        (0..32).any(|i| {
+            run = if states[i % 16] == expected { run + 1 } else { 0 };
+            run >= 9
+        })
+    });
+    if is_corner {
+        score
+    } else {
+        0
+    }
+}
+
+/// Direction from keypoint to intensity centroid of its patch
+fn orientation(pixels: &[u8], width: usize, x: i32, y: i32) -> f32 {
+    let (mut m01, mut m10) = (0i64, 0i64);
+    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+            if dx * dx + dy * dy > PATCH_RADIUS * PATCH_RADIUS {
+                continue;
+            }
+            let value = i64::from(pixels[(y + dy) as usize * width + (x + dx) as usize]);
+            m10 += i64::from(dx) * value;
+            m01 += i64::from(dy) * value;
+        }
+    }
+    (m01 as f32).atan2(m10 as f32)
+}
+
+fn describe(smoothed: &[u8], width: usize, x: i32, y: i32, angle: f32, pairs: &[(i32, i32, i32, i32)]) -> [u64; 4] {
+    let (sin, cos) = angle.sin_cos();
+    let at = |dx: i32, dy: i32| {
+        let rx = (cos * dx as f32 - sin * dy as f32).round() as i32;
+        let ry = (sin * dx as f32 + cos * dy as f32).round() as i32;
+        smoothed[(y + ry) as usize * width + (x + rx) as usize]
+    };
+    let mut descriptor = [0u64; 4];
+    for (i, &(x1, y1, x2, y2)) in pairs.iter().enumerate() {
+        if at(x1, y1) < at(x2, y2) {
+            descriptor[i / 64] |= 1 << (i % 64);
+        }
+    }
+    descriptor
+}
+
+/// Fixed pseudo-random point pairs, the same for every image
+fn sampling_pairs() -> Vec<(i32, i32, i32, i32)> {
+    let mut random = Lcg::new(0x5EED);
+    let size = (2 * PATCH_RADIUS + 1) as usize;
+    let mut coordinate = || random.next_below(size) as i32 - PATCH_RADIUS;
+    (0..DESCRIPTOR_PAIRS)
+        .map(|_| (coordinate(), coordinate(), coordinate(), coordinate()))
+        .collect()
+}
+
+/// 5x5 mean filter, descriptors compare smoothed pixels to be less sensitive to noise
+fn box_blur(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut integral = vec![0u32; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row_sum = 0;
+        for x in 0..width {
+            row_sum += u32::from(pixels[y * width + x]);
+            integral[(y + 1) * (width + 1) + x + 1] = integral[y * (width + 1) + x + 1] + row_sum;
+        }
+    }
+    let mut smoothed = vec![0u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let (top, bottom) = (y.saturating_sub(2), (y + 3).min(height));
+            let (left, right) = (x.saturating_sub(2), (x + 3).min(width));
+            let sum = integral[bottom * (width + 1) + right] + integral[top * (width + 1) + left]
+                - integral[top * (width + 1) + right]
+                - integral[bottom * (width + 1) + left];
+            smoothed[y * width + x] = (sum / ((bottom - top) * (right - left)) as u32) as u8;
+        }
+    }
+    smoothed
+}
+
+type Point = (f32, f32);
+
+/// Pairs of matched keypoint positions, query first
+fn match_descriptors(query: &Features, train: &Features) -> Vec<(Point, Point)> {
+    let mut matches = Vec::new();
+    for (point, descriptor) in query.points.iter().zip(query.descriptors.iter()) {
+        let (mut best, mut second, mut best_index) = (std::u32::MAX, std::u32::MAX, 0);
+        for (i, other) in train.descriptors.iter().enumerate() {
+            let distance = descriptor
+                .iter()
+                .zip(other.iter())
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum::<u32>();
+            if distance < best {
+                second = best;
+                best = distance;
+                best_index = i;
+            } else if distance < second {
+                second = distance;
+            }
+        }
+        if best <= MAX_DESCRIPTOR_DISTANCE && f64::from(best) < RATIO * f64::from(second) {
+            matches.push((*point, train.points[best_index]));
+        }
+    }
+    matches
+}
+
+/// Rotation, uniform scale and translation, `a` is scale and rotation as a complex number
+#[derive(Debug, Clone, Copy)]
+struct Similarity {
+    a: (f32, f32),
+    t: (f32, f32),
+}
+
+impl Similarity {
+    fn from_pairs((q1, t1): (Point, Point), (q2, t2): (Point, Point)) -> Option<Self> {
+        let dq = (q2.0 - q1.0, q2.1 - q1.1);
+        let dt = (t2.0 - t1.0, t2.1 - t1.1);
+        let norm = dq.0 * dq.0 + dq.1 * dq.1;
+        if norm < 1.0 {
+            return None;
+        }
+        let a = ((dt.0 * dq.0 + dt.1 * dq.1) / norm, (dt.1 * dq.0 - dt.0 * dq.1) / norm);
+        let scale = (a.0 * a.0 + a.1 * a.1).sqrt();
+        if scale < 0.1 || scale > 10.0 {
+            return None;
+        }
+        let rotated = (a.0 * q1.0 - a.1 * q1.1, a.1 * q1.0 + a.0 * q1.1);
+        Some(Self {
+            a,
+            t: (t1.0 - rotated.0, t1.1 - rotated.1),
+        })
+    }
+
+    fn apply(&self, (x, y): Point) -> Point {
+        (
+            self.a.0 * x - self.a.1 * y + self.t.0,
+            self.a.1 * x + self.a.0 * y + self.t.1,
+        )
+    }
+}
+
+fn distance(a: Point, b: Point) -> f32 {
+    ((a.0 - b.0) * (a.0 - b.0) + (a.1 - b.1) * (a.1 - b.1)).sqrt()
+}
+
+/// Deterministic generator, so the same images always give the same result
+struct Lcg(u64);
+
+impl Lcg {
+    fn new(seed: u64) -> Self {
+        Lcg(seed)
+    }
+
+    fn next_below(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((self.0 >> 33) % bound as u64) as usize
+    }
+}
@@ -0,0 +1,60 @@
+/// Image or video file format, as detected from magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Bmp,
+    Tiff,
+    WebP,
+    Mp4,
+    WebM,
+}
+
+impl ImageFormat {
+    /// Detects format by file signature, regardless of file name
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        let format = if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            ImageFormat::Jpeg
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1A\n") {
+            ImageFormat::Png
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            ImageFormat::Gif
+        } else if bytes.starts_with(b"BM") {
+            ImageFormat::Bmp
+        } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+            ImageFormat::Tiff
+        } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+            ImageFormat::WebP
+        } else if bytes.len() >= 8 && &bytes[4..8] == b"ftyp" {
+            ImageFormat::Mp4
+        } else if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            ImageFormat::WebM
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+
+    /// Usual file extension of the format, without dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Mp4 => "mp4",
+            ImageFormat::WebM => "webm",
+        }
+    }
+
+    /// Whether OpenCV reads files of this format with `VideoCapture` rather than `image_decode`
+    pub fn is_video(self) -> bool {
+        match self {
+            ImageFormat::Mp4 | ImageFormat::WebM => true,
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use cv::hash::{self as cv_hash, Hash};
+use cv::imgproc::*;
+use cv::*;
+use serde_derive::{Deserialize, Serialize};
+
+/// Hash of a single image, as produced by some `PerceptualHasher`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ImageHash {
+    /// Packed bit string, compared by Hamming distance
+    Binary(Vec<u8>),
+    /// Vector of real-valued features
+    Real(Vec<f64>),
+    /// Hashes of several algorithms computed for the same image
+    Composite(Vec<ImageHash>),
+}
+
+impl ImageHash {
+    /// Number of differing bits between two binary hashes of the same length
+    pub fn hamming_distance(&self, other: &ImageHash) -> Option<u32> {
+        match (self, other) {
+            (ImageHash::Binary(a), ImageHash::Binary(b)) if a.len() == b.len() => {
+                Some(a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Algorithm that turns a decoded image into a compact hash and tells how far apart two hashes are
+pub trait PerceptualHasher: Send + Sync {
+    /// Name of the algorithm, the same one `hasher_by_name` accepts
+    fn name(&self) -> String;
+
+    /// Version of hash format. Cached hashes with another version are recomputed
+    fn version(&self) -> u32 {
+        1
+    }
+
+    /// Computes hash of a decoded BGR image
+    fn compute(&self, image: &Mat) -> ImageHash;
+
+    /// Returns distance between two hashes computed by this hasher, zero means identical images
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64;
+
+    /// Distance below which two images are considered the same picture
+    fn default_threshold(&self) -> f64;
+
+    /// Whether hashes are always `ImageHash::Binary` and `compare` returns Hamming distance between them
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    /// Side of the thumbnail the image is resized to before hashing. Images may be decoded at reduced resolution
+    /// as long as both sides stay at least that large, `None` means full resolution is needed
+    fn working_size(&self) -> Option<i32> {
+        None
+    }
+}
+
+impl<H: PerceptualHasher + ?Sized> PerceptualHasher for Box<H> {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn version(&self) -> u32 {
+        (**self).version()
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        (**self).compute(image)
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        (**self).compare(lhs, rhs)
+    }
+
+    fn default_threshold(&self) -> f64 {
+        (**self).default_threshold()
+    }
+
+    fn is_binary(&self) -> bool {
+        (**self).is_binary()
+    }
+
+    fn working_size(&self) -> Option<i32> {
+        (**self).working_size()
+    }
+}
+
+/// Returns hasher by its name, as used in configuration
+pub fn hasher_by_name(name: &str) -> Option<Box<dyn PerceptualHasher>> {
+    let hasher: Box<dyn PerceptualHasher> = match name {
+        "average" => Box::new(AverageHasher::new()),
+        "difference" => Box::new(DifferenceHasher::new()),
+        "dct" => Box::new(DctHasher::new()),
+        "block-mean" => Box::new(BlockMeanHasher::new()),
+        "marr-hildreth" => Box::new(MarrHildrethHasher::new()),
+        "radial-variance" => Box::new(RadialVarianceHasher::new()),
+        "color-moment" => Box::new(ColorMomentHasher::new()),
+        _ => return None,
+    };
+    Some(hasher)
+}
+
+// OpenCV hash objects are created per call, so hashers stay plain values that are safe to share between threads
+macro_rules! impl_binary_hasher {
+    ($name:ident, $cv_hash:ident, $algorithm:expr, $threshold:expr, $working_size:expr, $description:expr) => {
+        #[doc = $description]
+        #[derive(Debug, Clone, Copy, Default)]
+        pub struct $name;
+
+        impl $name {
+            pub fn new() -> Self {
+                $name
+            }
+        }
+
+        impl PerceptualHasher for $name {
+            fn name(&self) -> String {
+                $algorithm.to_string()
+            }
+
+            fn compute(&self, image: &Mat) -> ImageHash {
+                let hash = cv_hash::$cv_hash::new().compute(image);
+                ImageHash::Binary(hash.data().to_vec())
+            }
+
+            fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+                hamming_or_infinity(lhs, rhs)
+            }
+
+            fn default_threshold(&self) -> f64 {
+                $threshold
+            }
+
+            fn is_binary(&self) -> bool {
+                true
+            }
+
+            fn working_size(&self) -> Option<i32> {
+                Some($working_size)
+            }
+        }
+    };
+}
+
+impl_binary_hasher!(
+    AverageHasher,
+    AverageHash,
+    "average",
+    5.0,
+    8,
+    "Average hash: 8x8 grayscale thumbnail thresholded by its mean, 64 bits"
+);
+impl_binary_hasher!(
+    DctHasher,
+    PHash,
+    "dct",
+    10.0,
+    32,
+    "Perceptual hash: low frequencies of DCT of 32x32 grayscale thumbnail, 64 bits"
+);
+impl_binary_hasher!(
+    BlockMeanHasher,
+    BlockMeanHash,
+    "block-mean",
+    35.0,
+    256,
+    "Block mean hash: 16x16 block means compared to their median, 256 bits"
+);
+impl_binary_hasher!(
+    MarrHildrethHasher,
+    MarrHildrethHash,
+    "marr-hildreth",
+    115.0,
+    512,
+    "Marr-Hildreth hash: edge response of Marr-Hildreth operator, 576 bits"
+);
+
+/// Difference hash: sign of horizontal gradient of 9x8 grayscale thumbnail, 64 bits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DifferenceHasher;
+
+impl DifferenceHasher {
+    const WIDTH: i32 = 9;
+    const HEIGHT: i32 = 8;
+
+    pub fn new() -> Self {
+        DifferenceHasher
+    }
+}
+
+impl PerceptualHasher for DifferenceHasher {
+    fn name(&self) -> String {
+        "difference".to_string()
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        let gray = image.cvt_color(ColorConversion::BGR2GRAY);
+        let thumbnail = gray.resize_to(Size2i::new(Self::WIDTH, Self::HEIGHT), InterpolationFlag::InterArea);
+        let pixels = thumbnail.data();
+        let width = Self::WIDTH as usize;
+        let mut bits = vec![0u8; Self::HEIGHT as usize];
+        for (row, byte) in bits.iter_mut().enumerate() {
+            for col in 0..width - 1 {
+                let offset = row * width + col;
+                if pixels[offset] < pixels[offset + 1] {
+                    *byte |= 1 << col;
+                }
+            }
+        }
+        ImageHash::Binary(bits)
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        hamming_or_infinity(lhs, rhs)
+    }
+
+    fn default_threshold(&self) -> f64 {
+        10.0
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn working_size(&self) -> Option<i32> {
+        Some(Self::WIDTH)
+    }
+}
+
+/// Radial variance hash: variance of pixels along 180 projection lines, 40 values.
+///
+/// Distance is `1 - peak cross-correlation`, so it stays in `[0, 2]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RadialVarianceHasher;
+
+impl RadialVarianceHasher {
+    pub fn new() -> Self {
+        RadialVarianceHasher
+    }
+}
+
+impl PerceptualHasher for RadialVarianceHasher {
+    fn name(&self) -> String {
+        "radial-variance".to_string()
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        let hash = cv_hash::RadialVarianceHash::new().compute(image);
+        ImageHash::Real(hash.data().iter().map(|&x| f64::from(x)).collect())
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        match (lhs, rhs) {
+            (ImageHash::Real(a), ImageHash::Real(b)) if a.len() == b.len() && !a.is_empty() => {
+                1.0 - peak_cross_correlation(a, b)
+            }
+            _ => std::f64::INFINITY,
+        }
+    }
+
+    fn default_threshold(&self) -> f64 {
+        0.1
+    }
+}
+
+/// Color moment hash: Hu moments of HSV and YCrCb channels, 42 values compared by euclidean distance
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColorMomentHasher;
+
+impl ColorMomentHasher {
+    pub fn new() -> Self {
+        ColorMomentHasher
+    }
+}
+
+impl PerceptualHasher for ColorMomentHasher {
+    fn name(&self) -> String {
+        "color-moment".to_string()
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        let hash = cv_hash::ColorMomentHash::new().compute(image);
+        let values = hash
+            .data()
+            .chunks(8)
+            .map(|chunk| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(chunk);
+                f64::from_bits(u64::from_ne_bytes(bytes))
+            })
+            .collect();
+        ImageHash::Real(values)
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        match (lhs, rhs) {
+            (ImageHash::Real(a), ImageHash::Real(b)) if a.len() == b.len() => a
+                .iter()
+                .zip(b.iter())
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f64>()
+                .sqrt(),
+            _ => std::f64::INFINITY,
+        }
+    }
+
+    fn default_threshold(&self) -> f64 {
+        1.0
+    }
+
+    fn working_size(&self) -> Option<i32> {
+        Some(512)
+    }
+}
+
+fn hamming_or_infinity(lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+    lhs.hamming_distance(rhs).map(f64::from).unwrap_or(std::f64::INFINITY)
+}
+
+/// Same normalized cross-correlation OpenCV uses for radial variance digests
+fn peak_cross_correlation(a: &[f64], b: &[f64]) -> f64 {
+    let len = a.len();
+    let (mean_a, std_a) = mean_std_dev(a);
+    let (mean_b, std_b) = mean_std_dev(b);
+    let denominator = std_a * std_b * len as f64;
+    if denominator == 0.0 {
+        return if a == b { 1.0 } else { 0.0 };
+    }
+    (0..len)
+        .map(|shift| {
+            let numerator: f64 = (0..len)
+                .map(|j| (a[j] - mean_a) * (b[(len + j - shift) % len] - mean_b))
+                .sum();
+            numerator / denominator
+        })
+        .fold(std::f64::MIN, f64::max)
+}
+
+fn mean_std_dev(values: &[f64]) -> (f64, f64) {
+    let len = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / len;
+    let variance = values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / len;
+    (mean, variance.sqrt())
+}
@@ -0,0 +1,238 @@
+use crate::format::ImageFormat;
+use crate::media::read_video_dimensions;
+
+/// Size of image in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    pub fn pixels(self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+}
+
+/// Images over these limits are rejected before decoding, so a small file can't make decoder allocate gigabytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    /// Checked for every frame of animations and videos
+    pub max_pixels: u64,
+    /// Pixels of all frames decoded from an animation or a video. Only keyframes up to it are compared
+    pub max_decoded_pixels: u64,
+    pub max_bytes: usize,
+}
+
+impl Default for ImageLimits {
+    /// Telegram bots can't download larger files anyway, and 50 megapixels take 150 MB decoded.
+    /// Two gigapixels are a minute of 720p video, which is longer than keyframes cover
+    fn default() -> Self {
+        Self {
+            max_pixels: 50_000_000,
+            max_decoded_pixels: 2_000_000_000,
+            max_bytes: 20 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why image was not decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyBytes {
+        bytes: usize,
+        limit: usize,
+    },
+    TooManyPixels {
+        pixels: u64,
+        limit: u64,
+    },
+    /// Image header is truncated or corrupt, so its size is unknown
+    UnreadableHeader,
+}
+
+impl ImageLimits {
+    /// Checks file size and image size from its header. Videos are opened to read their frame size,
+    /// bytes of unknown format are let through since `ImageDb` never decodes them
+    pub fn check(&self, bytes: &[u8]) -> Result<(), Rejection> {
+        if bytes.len() > self.max_bytes {
+            return Err(Rejection::TooManyBytes {
+                bytes: bytes.len(),
+                limit: self.max_bytes,
+            });
+        }
+        let dimensions = match ImageFormat::detect(bytes) {
+            Some(format) if format.is_video() => read_video_dimensions(bytes),
+            Some(_) => read_dimensions(bytes),
+            None => return Ok(()),
+        };
+        let pixels = dimensions.ok_or(Rejection::UnreadableHeader)?.pixels();
+        if pixels > self.max_pixels {
+            return Err(Rejection::TooManyPixels {
+                pixels,
+                limit: self.max_pixels,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Reads image size from its header without decoding pixels. Returns `None` for videos and unknown formats
+pub fn read_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    match ImageFormat::detect(bytes)? {
+        ImageFormat::Jpeg => read_jpeg_dimensions(bytes),
+        ImageFormat::Png => read_png_dimensions(bytes),
+        ImageFormat::Gif => Some(Dimensions {
+            width: u32::from(read_u16_le(bytes.get(6..8)?)),
+            height: u32::from(read_u16_le(bytes.get(8..10)?)),
+        }),
+        ImageFormat::Bmp => read_bmp_dimensions(bytes),
+        ImageFormat::Tiff => read_tiff_dimensions(bytes),
+        ImageFormat::WebP => read_webp_dimensions(bytes),
+        ImageFormat::Mp4 | ImageFormat::WebM => None,
+    }
+}
+
+/// Walks JPEG markers up to the start of frame, which holds image size
+fn read_jpeg_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    let mut position = 2;
+    loop {
+        while *bytes.get(position)? != 0xFF {
+            position += 1;
+        }
+        // Markers may be padded with any number of 0xFF
+        while *bytes.get(position)? == 0xFF {
+            position += 1;
+        }
+        let marker = *bytes.get(position)?;
+        position += 1;
+        match marker {
+            // Markers without payload
+            0x01 | 0xD0..=0xD8 => continue,
+            // Start of frame of any coding process, except huffman tables and arithmetic coding conditioning
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let header = bytes.get(position..position + 7)?;
+                return Some(Dimensions {
+                    width: u32::from(read_u16_be(&header[5..7])),
+                    height: u32::from(read_u16_be(&header[3..5])),
+                });
+            }
+            0xD9 | 0xDA => return None,
+            _ => {
+                let length = read_u16_be(bytes.get(position..position + 2)?);
+                position += usize::from(length);
+            }
+        }
+    }
+}
+
+/// Size is in IHDR chunk, which always goes first
+fn read_png_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    if bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    Some(Dimensions {
+        width: read_u32_be(bytes.get(16..20)?),
+        height: read_u32_be(bytes.get(20..24)?),
+    })
+}
+
+/// Old OS/2 header has 16-bit size, newer ones have signed 32-bit size with negative height for top-down images
+fn read_bmp_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    let header_size = read_u32_le(bytes.get(14..18)?);
+    if header_size == 12 {
+        return Some(Dimensions {
+            width: u32::from(read_u16_le(bytes.get(18..20)?)),
+            height: u32::from(read_u16_le(bytes.get(20..22)?)),
+        });
+    }
+    let width = read_u32_le(bytes.get(18..22)?) as i32;
+    let height = read_u32_le(bytes.get(22..26)?) as i32;
+    Some(Dimensions {
+        width: width.checked_abs()? as u32,
+        height: height.checked_abs()? as u32,
+    })
+}
+
+/// Size is in width and height tags of the first image file directory
+fn read_tiff_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    let little_endian = bytes.starts_with(b"II");
+    let read_u16 = |from: usize| {
+        let field = bytes.get(from..from + 2)?;
+        Some(if little_endian {
+            read_u16_le(field)
+        } else {
+            read_u16_be(field)
+        })
+    };
+    let read_u32 = |from: usize| {
+        let field = bytes.get(from..from + 4)?;
+        Some(if little_endian {
+            read_u32_le(field)
+        } else {
+            read_u32_be(field)
+        })
+    };
+    let directory = read_u32(4)? as usize;
+    let (mut width, mut height) = (None, None);
+    for i in 0..usize::from(read_u16(directory)?) {
+        let entry = directory + 2 + i * 12;
+        // Size may be either SHORT or LONG
+        let value = match read_u16(entry + 2)? {
+            3 => u32::from(read_u16(entry + 8)?),
+            4 => read_u32(entry + 8)?,
+            _ => continue,
+        };
+        match read_u16(entry)? {
+            256 => width = Some(value),
+            257 => height = Some(value),
+            _ => {}
+        }
+    }
+    Some(Dimensions {
+        width: width?,
+        height: height?,
+    })
+}
+
+/// Lossy, lossless and extended WebP keep size in different places of their first chunk
+fn read_webp_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    match bytes.get(12..16)? {
+        b"VP8 " => Some(Dimensions {
+            width: u32::from(read_u16_le(bytes.get(26..28)?) & 0x3FFF),
+            height: u32::from(read_u16_le(bytes.get(28..30)?) & 0x3FFF),
+        }),
+        b"VP8L" => {
+            let bits = read_u32_le(bytes.get(21..25)?);
+            Some(Dimensions {
+                width: (bits & 0x3FFF) + 1,
+                height: ((bits >> 14) & 0x3FFF) + 1,
+            })
+        }
+        b"VP8X" => Some(Dimensions {
+            width: read_u24_le(bytes.get(24..27)?) + 1,
+            height: read_u24_le(bytes.get(27..30)?) + 1,
+        }),
+        _ => None,
+    }
+}
+
+fn read_u16_le(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
+}
+
+fn read_u24_le(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) | (u32::from(bytes[2]) << 16)
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    read_u24_le(bytes) | (u32::from(bytes[3]) << 24)
+}
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+    (u32::from(read_u16_be(&bytes[0..2])) << 16) | u32::from(read_u16_be(&bytes[2..4]))
+}
+
+fn read_u16_be(bytes: &[u8]) -> u16 {
+    (u16::from(bytes[0]) << 8) | u16::from(bytes[1])
+}
@@ -0,0 +1,152 @@
+use std::collections::BinaryHeap;
+
+/// BK-tree over Hamming distance between binary hashes of equal length.
+///
+/// Triangle inequality lets queries skip whole subtrees, so threshold lookups only visit a small part of the tree
+/// when the radius is small compared to hash length.
+#[derive(Debug, Clone, Default)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+    removed: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    hash: Vec<u8>,
+    /// `None` for removed items, their nodes stay to keep children reachable
+    item: Option<usize>,
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            removed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether removed items take more than half of the tree, so it is worth building again
+    pub fn is_sparse(&self) -> bool {
+        self.removed > self.nodes.len() / 2
+    }
+
+    /// Adds `hash` that identifies `item`
+    pub fn insert(&mut self, hash: Vec<u8>, item: usize) {
+        let new_index = self.nodes.len();
+        if new_index > 0 {
+            let mut current = 0;
+            loop {
+                let distance = hamming_distance(&self.nodes[current].hash, &hash);
+                let child = self.nodes[current]
+                    .children
+                    .iter()
+                    .find(|&&(edge, _)| edge == distance)
+                    .map(|&(_, child)| child);
+                match child {
+                    Some(child) => current = child,
+                    None => {
+                        self.nodes[current].children.push((distance, new_index));
+                        break;
+                    }
+                }
+            }
+        }
+        self.nodes.push(Node {
+            hash,
+            item: Some(item),
+            children: Vec::new(),
+        });
+    }
+
+    /// Removes `item` identified by `hash`, returns whether it was found
+    pub fn remove(&mut self, hash: &[u8], item: usize) -> bool {
+        match self.find_node(hash, item) {
+            Some(node) => {
+                self.nodes[node].item = None;
+                self.removed += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes `hash` identify `new_item` instead of `old_item`, returns whether it was found
+    pub fn replace(&mut self, hash: &[u8], old_item: usize, new_item: usize) -> bool {
+        match self.find_node(hash, old_item) {
+            Some(node) => {
+                self.nodes[node].item = Some(new_item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Nodes with equal hashes are chained by zero distance edges, so the search never branches
+    fn find_node(&self, hash: &[u8], item: usize) -> Option<usize> {
+        let mut current = if self.nodes.is_empty() { None } else { Some(0) };
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            let distance = hamming_distance(&node.hash, hash);
+            if distance == 0 && node.item == Some(item) {
+                return Some(index);
+            }
+            current = node
+                .children
+                .iter()
+                .find(|&&(edge, _)| edge == distance)
+                .map(|&(_, child)| child);
+        }
+        None
+    }
+
+    /// Returns up to `k` items closest to `hash` with distance not greater than `radius`, closest first
+    pub fn find_nearest(&self, hash: &[u8], k: usize, radius: u32) -> Vec<(u32, usize)> {
+        if self.nodes.is_empty() || k == 0 {
+            return Vec::new();
+        }
+        let mut best = BinaryHeap::with_capacity(k + 1);
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let distance = hamming_distance(&node.hash, hash);
+            let mut radius = current_radius(&best, k, radius);
+            match node.item {
+                Some(item) if distance <= radius => {
+                    best.push((distance, item));
+                    if best.len() > k {
+                        best.pop();
+                    }
+                    radius = current_radius(&best, k, radius);
+                }
+                _ => {}
+            }
+            for &(edge, child) in node.children.iter() {
+                if edge.saturating_add(radius) >= distance && edge <= distance.saturating_add(radius) {
+                    stack.push(child);
+                }
+            }
+        }
+        best.into_sorted_vec()
+    }
+}
+
+fn current_radius(best: &BinaryHeap<(u32, usize)>, k: usize, radius: u32) -> u32 {
+    if best.len() < k {
+        radius
+    } else {
+        best.peek().map(|&(distance, _)| distance.min(radius)).unwrap_or(radius)
+    }
+}
+
+fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
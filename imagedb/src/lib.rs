@@ -1,14 +1,58 @@
-use cv::hash::*;
+mod ensemble;
+mod error;
+mod features;
+mod format;
+mod hasher;
+mod header;
+mod index;
+mod media;
+mod tier;
+mod tiles;
+mod transform;
+mod trim;
+
+pub use crate::ensemble::*;
+pub use crate::error::*;
+pub use crate::features::*;
+pub use crate::format::*;
+pub use crate::hasher::*;
+pub use crate::header::*;
+pub use crate::index::*;
+pub use crate::media::*;
+pub use crate::tier::*;
+pub use crate::tiles::*;
+pub use crate::transform::*;
+pub use crate::trim::*;
 use cv::imgcodecs::*;
+use cv::imgproc::*;
 use cv::*;
+use log::warn;
+use rayon::prelude::*;
 use serde;
+use serde_derive::{Deserialize, Serialize};
 use serde_json;
+use sha2::{Digest, Sha256};
 use std::cmp::PartialEq;
+use std::collections::{BinaryHeap, HashMap};
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Write};
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const METADATA_EXTENSION: &str = "json";
+const HASH_EXTENSION: &str = "hash";
+const THUMBNAIL_EXTENSION: &str = "thumb";
+const DIGEST_EXTENSION: &str = "sha256";
+const TIMESTAMP_EXTENSION: &str = "time";
+const THUMBNAIL_SIZE: i32 = 256;
+/// Images with stale hashes are read into memory in batches of this size before they are hashed in parallel
+const LOAD_BATCH_SIZE: usize = 256;
+/// Incremented when `CachedHash` gets data that older caches lack or keep differently, e.g. keyframes of animations
+/// or tile regions in pixels of the original image. Such caches are recomputed on load, or used as they are
+/// if image bytes are not stored
+const CACHE_REVISION: u32 = 2;
 
 pub trait Metadata: Clone {
     fn file_name(&self) -> &str;
@@ -26,28 +70,162 @@ impl<T: Metadata> Image<T> {
     }
 }
 
+/// Hash saved along with the image, tagged with algorithm that computed it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedHash {
+    pub algorithm: String,
+    pub version: u32,
+    pub hash: ImageHash,
+    /// Hashes of parts of the image, if it was split into tiles
+    #[serde(default)]
+    pub tiles: Vec<TileHash>,
+    /// How the image was prepared before hashing
+    #[serde(default)]
+    pub preprocessing: Preprocessing,
+    /// Hash that tells re-encoded copies from the same template with another text, missing in older caches
+    #[serde(default)]
+    pub detail: Option<ImageHash>,
+    /// Hashes of keyframes, if the image is animated or is a video
+    #[serde(default)]
+    pub frames: Vec<ImageHash>,
+    /// Missing in caches written before keyframes were hashed, see `CACHE_REVISION` for what changed since
+    #[serde(default)]
+    pub revision: u32,
+}
+
+impl CachedHash {
+    pub fn new<H: PerceptualHasher + ?Sized>(
+        hasher: &H,
+        hash: ImageHash,
+        tiles: Vec<TileHash>,
+        preprocessing: Preprocessing,
+        detail: ImageHash,
+        frames: Vec<ImageHash>,
+    ) -> Self {
+        Self {
+            algorithm: hasher.name(),
+            version: hasher.version(),
+            hash,
+            tiles,
+            preprocessing,
+            detail: Some(detail),
+            frames,
+            revision: CACHE_REVISION,
+        }
+    }
+
+    /// Whether the hash was computed by the same algorithm of the same version from the same way prepared image
+    pub fn is_computed_by<H: PerceptualHasher + ?Sized>(&self, hasher: &H, preprocessing: Preprocessing) -> bool {
+        self.algorithm == hasher.name() && self.version == hasher.version() && self.preprocessing == preprocessing
+    }
+
+    /// Whether the cache lacks something current version computes, so it is incomplete even if hashes match
+    pub fn is_outdated(&self) -> bool {
+        self.revision < CACHE_REVISION
+    }
+}
+
+/// How images are prepared before hashing. Cached hashes of differently prepared images are recomputed on load
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preprocessing {
+    /// Cut off uniform borders, frames and caption bands
+    pub trim_borders: bool,
+    /// Hash grid cells and panels of images too, to find pictures inside collages
+    pub tiles: bool,
+}
+
+/// Saved image without its bytes
+#[derive(Debug, Clone)]
+pub struct StoredEntry<T: Metadata> {
+    pub metadata: T,
+    pub hash: Option<CachedHash>,
+    /// SHA-256 of original image bytes, if it was saved
+    pub digest: Option<Vec<u8>>,
+    /// When the image was saved
+    pub inserted_at: SystemTime,
+}
+
 pub trait Storage<T: Metadata> {
-    fn save_image(&mut self, image: &Image<T>);
-    fn load_images(&self) -> Vec<Image<T>>;
+    fn save_image(&mut self, image: &Image<T>) -> Result<(), ImageDbError>;
+    fn load_images(&self) -> Result<Vec<Image<T>>, ImageDbError>;
+    /// Saves hash of already saved image, replacing the previous one
+    fn save_hash(&mut self, metadata: &T, hash: &CachedHash) -> Result<(), ImageDbError>;
+    /// Saves content digest of already saved image
+    fn save_digest(&mut self, metadata: &T, digest: &[u8]) -> Result<(), ImageDbError>;
+    /// Loads metadata of all saved images along with their cached hashes, without reading image bytes
+    fn load_entries(&self) -> Result<Vec<StoredEntry<T>>, ImageDbError>;
+    /// Loads image bytes, if storage still has them
+    fn load_image(&self, metadata: &T) -> Result<Option<Image<T>>, ImageDbError>;
+    /// Removes image with the same file name along with its hash, does nothing if there is no such image
+    fn remove_image(&mut self, metadata: &T) -> Result<(), ImageDbError>;
 }
 
 pub struct InMemoryStorage<T: Metadata> {
-    images: Vec<Image<T>>,
+    images: Vec<(Image<T>, SystemTime)>,
+    hashes: HashMap<String, CachedHash>,
+    digests: HashMap<String, Vec<u8>>,
 }
 
 impl<T: Metadata> InMemoryStorage<T> {
     pub fn new() -> Self {
-        Self { images: Vec::new() }
+        Self {
+            images: Vec::new(),
+            hashes: HashMap::new(),
+            digests: HashMap::new(),
+        }
     }
 }
 
 impl<T: Metadata> Storage<T> for InMemoryStorage<T> {
-    fn save_image(&mut self, image: &Image<T>) {
-        self.images.push(image.clone());
+    fn save_image(&mut self, image: &Image<T>) -> Result<(), ImageDbError> {
+        self.images.push((image.clone(), SystemTime::now()));
+        Ok(())
+    }
+
+    fn load_images(&self) -> Result<Vec<Image<T>>, ImageDbError> {
+        Ok(self.images.iter().map(|(image, _)| image.clone()).collect())
+    }
+
+    fn save_hash(&mut self, metadata: &T, hash: &CachedHash) -> Result<(), ImageDbError> {
+        self.hashes.insert(metadata.file_name().to_string(), hash.clone());
+        Ok(())
+    }
+
+    fn save_digest(&mut self, metadata: &T, digest: &[u8]) -> Result<(), ImageDbError> {
+        self.digests.insert(metadata.file_name().to_string(), digest.to_vec());
+        Ok(())
     }
 
-    fn load_images(&self) -> Vec<Image<T>> {
-        self.images.clone()
+    fn load_entries(&self) -> Result<Vec<StoredEntry<T>>, ImageDbError> {
+        let entries = self
+            .images
+            .iter()
+            .map(|(image, inserted_at)| StoredEntry {
+                metadata: image.metadata.clone(),
+                hash: self.hashes.get(image.metadata.file_name()).cloned(),
+                digest: self.digests.get(image.metadata.file_name()).cloned(),
+                inserted_at: *inserted_at,
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn load_image(&self, metadata: &T) -> Result<Option<Image<T>>, ImageDbError> {
+        let image = self
+            .images
+            .iter()
+            .find(|(image, _)| image.metadata.file_name() == metadata.file_name())
+            .map(|(image, _)| image.clone());
+        Ok(image)
+    }
+
+    fn remove_image(&mut self, metadata: &T) -> Result<(), ImageDbError> {
+        self.images
+            .retain(|(image, _)| image.metadata.file_name() != metadata.file_name());
+        self.hashes.remove(metadata.file_name());
+        self.digests.remove(metadata.file_name());
+        Ok(())
     }
 }
 
@@ -55,6 +233,10 @@ impl<T: Metadata> Storage<T> for InMemoryStorage<T> {
 pub enum ImageVariant<T: Metadata> {
     New,
     AlreadyExists(T),
+    /// Bytes are not an image OpenCV could decode, nothing was saved
+    Undecodable,
+    /// Image is over limits and was not decoded, nothing was saved
+    Rejected(Rejection),
 }
 
 impl<T: Metadata + PartialEq> PartialEq for ImageVariant<T> {
@@ -62,56 +244,891 @@ impl<T: Metadata + PartialEq> PartialEq for ImageVariant<T> {
         match (self, other) {
             (ImageVariant::New, ImageVariant::New) => true,
             (ImageVariant::AlreadyExists(a), ImageVariant::AlreadyExists(b)) if a == b => true,
+            (ImageVariant::Undecodable, ImageVariant::Undecodable) => true,
+            (ImageVariant::Rejected(a), ImageVariant::Rejected(b)) => a == b,
             _ => false,
         }
     }
 }
 
-pub struct ImageDb<T: Metadata, D: Storage<T>> {
+/// Stored image close to the queried one
+#[derive(Debug, Clone)]
+pub struct SimilarImage<T: Metadata> {
+    pub metadata: T,
+    pub distance: f64,
+    /// Transform that turns the queried image into the stored one
+    pub transform: Transform,
+    /// Part of the queried image that was hashed, if borders or captions were trimmed
+    pub crop: Option<Crop>,
+    /// Number of geometrically consistent keypoint matches, if the image was confirmed by local features
+    pub feature_matches: Option<usize>,
+    /// Matched part of a collage, if images were matched by tiles
+    pub tile: Option<TileMatch>,
+    pub tier: MatchTier,
+}
+
+/// Same as `ImageVariant`, but keeps distances and all close enough images
+#[derive(Debug, Clone)]
+pub enum MatchResult<T: Metadata> {
+    New,
+    /// Images within threshold, closest first. Never empty
+    AlreadyExists(Vec<SimilarImage<T>>),
+    /// Bytes are not an image OpenCV could decode, nothing was saved
+    Undecodable,
+    /// Image is over limits and was not decoded, nothing was saved
+    Rejected(Rejection),
+}
+
+impl<T: Metadata> MatchResult<T> {
+    /// Returns the closest image, if any
+    pub fn best(&self) -> Option<&SimilarImage<T>> {
+        match self {
+            MatchResult::New | MatchResult::Undecodable | MatchResult::Rejected(_) => None,
+            MatchResult::AlreadyExists(images) => images.first(),
+        }
+    }
+}
+
+impl<T: Metadata> From<MatchResult<T>> for ImageVariant<T> {
+    fn from(result: MatchResult<T>) -> Self {
+        match result {
+            MatchResult::New => ImageVariant::New,
+            MatchResult::AlreadyExists(images) => {
+                match images.into_iter().find(|image| image.tier != MatchTier::SameTemplate) {
+                    Some(image) => ImageVariant::AlreadyExists(image.metadata),
+                    // Image with new text was saved
+                    None => ImageVariant::New,
+                }
+            }
+            MatchResult::Undecodable => ImageVariant::Undecodable,
+            MatchResult::Rejected(rejection) => ImageVariant::Rejected(rejection),
+        }
+    }
+}
+
+/// Image known to `ImageDb`
+struct KnownImage<T> {
+    hash: ImageHash,
+    tiles: Vec<TileHash>,
+    detail: Option<ImageHash>,
+    frames: Vec<ImageHash>,
+    digest: Option<Vec<u8>>,
+    metadata: T,
+    inserted_at: SystemTime,
+    /// Last time a saved image was found similar to it. Not persisted, starts at `inserted_at` after restart
+    last_matched: SystemTime,
+}
+
+impl<T> KnownImage<T> {
+    fn new(metadata: T, cached: CachedHash, digest: Option<Vec<u8>>, inserted_at: SystemTime) -> Self {
+        Self {
+            hash: cached.hash,
+            tiles: cached.tiles,
+            detail: cached.detail,
+            frames: cached.frames,
+            digest,
+            metadata,
+            inserted_at,
+            last_matched: inserted_at,
+        }
+    }
+}
+
+/// Hashes of transformed copies of the same image
+type TransformedHashes = Vec<(Transform, ImageHash)>;
+
+/// Decoded and prepared image that is looked up in the database
+struct Query {
+    mat: Mat,
+    crop: Option<Crop>,
+    /// How many times the image was reduced while decoding, tile regions are in pixels of the original image
+    factor: i32,
+    /// Identity goes first
+    hashes: TransformedHashes,
+    tiles: Vec<TileHash>,
+    detail: ImageHash,
+    /// Keyframe hashes, empty for still images
+    frames: Vec<ImageHash>,
+}
+
+/// Stored image close to the queried one, referred by position
+struct Candidate {
+    distance: f64,
+    position: usize,
+    transform: Transform,
+    crop: Option<Crop>,
+    feature_matches: Option<usize>,
+    tile: Option<TileMatch>,
+    tier: MatchTier,
+}
+
+impl Candidate {
+    fn exact(position: usize) -> Self {
+        Self {
+            distance: 0.0,
+            position,
+            transform: Transform::Identity,
+            crop: None,
+            feature_matches: None,
+            tile: None,
+            tier: MatchTier::Identical,
+        }
+    }
+
+    /// Candidate of the image as given, not transformed
+    fn new(distance: f64, position: usize, crop: Option<Crop>) -> Self {
+        Self {
+            distance,
+            position,
+            crop,
+            tier: MatchTier::ReEncoded,
+            ..Self::exact(position)
+        }
+    }
+}
+
+/// Second matching stage for images whose hashes are too far apart, e.g. crops and partial screenshots.
+///
+/// Stored images closest by hash are decoded and compared with the queried one by local features,
+/// so it only works when storage keeps image bytes or thumbnails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeatureVerification {
+    /// How many closest images are compared by features
+    pub candidates: usize,
+    /// Minimal number of geometrically consistent keypoint matches to confirm that images are the same
+    pub min_matches: usize,
+}
+
+/// Which images are evicted first when `ImageDb` is over capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    OldestFirst,
+    LeastRecentlyMatched,
+}
+
+/// How many stored images `ImageDb` has loaded so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+pub struct ImageDb<T: Metadata, D: Storage<T>, H: PerceptualHasher> {
     database: D,
-    hasher: ColorMomentHash,
-    images: Vec<(Mat, T)>,
+    hasher: H,
+    threshold: f64,
+    retention: Option<Duration>,
+    capacity: Option<usize>,
+    eviction_policy: EvictionPolicy,
+    transform_invariant: bool,
+    verification: Option<FeatureVerification>,
+    preprocessing: Preprocessing,
+    limits: ImageLimits,
+    images: Vec<KnownImage<T>>,
+    index: Option<BkTree>,
+    /// Positions of images by SHA-256 of their bytes, to match byte-identical copies without decoding
+    digests: HashMap<Vec<u8>, usize>,
 }
 
-impl<T: Metadata, D: Storage<T>> ImageDb<T, D> {
-    pub fn new(database: D) -> Self {
-        let hasher = ColorMomentHash::new();
-        let images = database
-            .load_images()
+impl<T: Metadata, D: Storage<T>, H: PerceptualHasher> ImageDb<T, D, H> {
+    pub fn new(database: D, hasher: H) -> Result<Self, ImageDbError> {
+        let threshold = hasher.default_threshold();
+        Self::with_threshold(database, hasher, threshold)
+    }
+
+    /// Creates database that considers images similar when distance between their hashes is below `threshold`
+    pub fn with_threshold(database: D, hasher: H, threshold: f64) -> Result<Self, ImageDbError> {
+        Self::with_preprocessing(database, hasher, threshold, Preprocessing::default())
+    }
+
+    /// Same as `with_threshold`, but images are prepared according to `preprocessing` before hashing
+    pub fn with_preprocessing(
+        database: D,
+        hasher: H,
+        threshold: f64,
+        preprocessing: Preprocessing,
+    ) -> Result<Self, ImageDbError> {
+        Self::with_progress(database, hasher, threshold, preprocessing, |_| {})
+    }
+
+    /// Same as `with_preprocessing`, `progress` is called on the calling thread after every batch of loaded images
+    pub fn with_progress<F: FnMut(LoadProgress)>(
+        database: D,
+        hasher: H,
+        threshold: f64,
+        preprocessing: Preprocessing,
+        progress: F,
+    ) -> Result<Self, ImageDbError> {
+        Self::with_limits(
+            database,
+            hasher,
+            threshold,
+            preprocessing,
+            ImageLimits::default(),
+            progress,
+        )
+    }
+
+    /// Same as `with_progress`, but stored images that have to be rehashed are checked against `limits` too
+    pub fn with_limits<F: FnMut(LoadProgress)>(
+        database: D,
+        hasher: H,
+        threshold: f64,
+        preprocessing: Preprocessing,
+        limits: ImageLimits,
+        progress: F,
+    ) -> Result<Self, ImageDbError> {
+        let mut db = Self {
+            database,
+            hasher,
+            threshold,
+            retention: None,
+            capacity: None,
+            eviction_policy: EvictionPolicy::OldestFirst,
+            transform_invariant: false,
+            verification: None,
+            preprocessing,
+            limits,
+            images: Vec::new(),
+            index: None,
+            digests: HashMap::new(),
+        };
+        db.load(progress)?;
+        Ok(db)
+    }
+
+    /// Loads images from storage, rehashing ones whose cached hashes were computed differently.
+    ///
+    /// Bytes of stale images are read in batches, then decoded and hashed on all cores.
+    fn load<F: FnMut(LoadProgress)>(&mut self, mut progress: F) -> Result<(), ImageDbError> {
+        let entries = self.database.load_entries()?;
+        let total = entries.len();
+        let mut images = Vec::with_capacity(total);
+        let mut entries = entries.into_iter().peekable();
+        let mut processed = 0;
+        while entries.peek().is_some() {
+            let mut stale_entries = Vec::new();
+            let mut stale_bytes = Vec::new();
+            for mut entry in entries.by_ref().take(LOAD_BATCH_SIZE) {
+                processed += 1;
+                let is_fresh = entry.hash.as_ref().map_or(false, |cached| {
+                    cached.is_computed_by(&self.hasher, self.preprocessing) && !cached.is_outdated()
+                });
+                if is_fresh {
+                    let cached = entry.hash.take().unwrap();
+                    images.push(KnownImage::new(entry.metadata, cached, entry.digest, entry.inserted_at));
+                    continue;
+                }
+                let bytes = match self.database.load_image(&entry.metadata)? {
+                    Some(ref image) if self.limits.check(&image.bytes).is_err() => {
+                        warn!(
+                            "Image {} is over limits and can't be rehashed",
+                            entry.metadata.file_name()
+                        );
+                        None
+                    }
+                    Some(image) => Some(image.bytes),
+                    None => None,
+                };
+                match (bytes, entry.hash.take()) {
+                    (Some(bytes), cached) => {
+                        entry.hash = cached;
+                        stale_entries.push(entry);
+                        stale_bytes.push(bytes);
+                    }
+                    // Outdated cache of the same algorithm is still better than nothing
+                    (None, Some(cached)) if cached.is_computed_by(&self.hasher, self.preprocessing) => {
+                        images.push(KnownImage::new(entry.metadata, cached, entry.digest, entry.inserted_at))
+                    }
+                    (None, _) => warn!(
+                        "Image {} has no up to date hash and no bytes to compute it, skipping",
+                        entry.metadata.file_name()
+                    ),
+                }
+            }
+            let (hasher, preprocessing, limits) = (&self.hasher, self.preprocessing, &self.limits);
+            let rehashed: Vec<_> = stale_bytes
+                .par_iter()
+                .map(|bytes| compute_cached_hash(hasher, preprocessing, limits, bytes))
+                .collect();
+            for (entry, cached) in stale_entries.into_iter().zip(rehashed) {
+                match cached {
+                    Some(mut cached) => {
+                        if let Some(previous) = entry.hash {
+                            self.keep_keyframes(entry.metadata.file_name(), &mut cached, previous);
+                        }
+                        self.database.save_hash(&entry.metadata, &cached)?;
+                        images.push(KnownImage::new(entry.metadata, cached, entry.digest, entry.inserted_at));
+                    }
+                    None => warn!("Image {} could not be decoded, skipping", entry.metadata.file_name()),
+                }
+            }
+            progress(LoadProgress { processed, total });
+        }
+        self.images = images;
+        self.rebuild_index();
+        Ok(())
+    }
+
+    /// Thumbnail of an animation is a still image, so keyframes computed from the original bytes are kept
+    /// if they were computed by the same algorithm
+    fn keep_keyframes(&self, file_name: &str, rehashed: &mut CachedHash, previous: CachedHash) {
+        if !rehashed.frames.is_empty() || previous.frames.is_empty() {
+            return;
+        }
+        if previous.is_computed_by(&self.hasher, self.preprocessing) {
+            rehashed.frames = previous.frames;
+        } else {
+            warn!(
+                "Animation {} was rehashed from a still image and lost its keyframes",
+                file_name
+            );
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    pub fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention
+    }
+
+    /// Sets how long images are kept. Older images are ignored when matching and evicted on the next save
+    pub fn set_retention(&mut self, retention: Option<Duration>) {
+        self.retention = retention;
+    }
+
+    /// Removes images older than retention period from memory and storage, returns their metadata
+    pub fn evict_expired(&mut self) -> Result<Vec<T>, ImageDbError> {
+        if self.retention.is_none() {
+            return Ok(Vec::new());
+        }
+        let retention = self.retention;
+        let now = SystemTime::now();
+        self.remove_matching(|image| is_expired(retention, image.inserted_at, now))
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Sets maximum number of images. Extra images are evicted on the next save
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    pub fn transform_invariant(&self) -> bool {
+        self.transform_invariant
+    }
+
+    /// Sets whether rotated by 90 degrees and mirrored copies of images are matched too.
+    /// Every queried image is hashed eight times then
+    pub fn set_transform_invariant(&mut self, transform_invariant: bool) {
+        self.transform_invariant = transform_invariant;
+    }
+
+    pub fn verification(&self) -> Option<FeatureVerification> {
+        self.verification
+    }
+
+    /// Enables comparison by local features for images that are not similar enough by hash
+    pub fn set_verification(&mut self, verification: Option<FeatureVerification>) {
+        self.verification = verification;
+    }
+
+    pub fn limits(&self) -> ImageLimits {
+        self.limits
+    }
+
+    /// Sets limits images are checked against before decoding. Stored images were already checked on load
+    pub fn set_limits(&mut self, limits: ImageLimits) {
+        self.limits = limits;
+    }
+
+    pub fn preprocessing(&self) -> Preprocessing {
+        self.preprocessing
+    }
+
+    /// Removes images beyond capacity from memory and storage according to eviction policy, returns their metadata
+    pub fn evict_over_capacity(&mut self) -> Result<Vec<T>, ImageDbError> {
+        let capacity = match self.capacity {
+            Some(capacity) if capacity < self.images.len() => capacity,
+            _ => return Ok(Vec::new()),
+        };
+        // Usually a single image is over capacity, so only the oldest ones are kept in a heap instead of sorting all
+        let excess = self.images.len() - capacity;
+        let mut evicted = BinaryHeap::with_capacity(excess + 1);
+        for (i, image) in self.images.iter().enumerate() {
+            let time = match self.eviction_policy {
+                EvictionPolicy::OldestFirst => image.inserted_at,
+                EvictionPolicy::LeastRecentlyMatched => image.last_matched,
+            };
+            evicted.push((time, i));
+            if evicted.len() > excess {
+                evicted.pop();
+            }
+        }
+        let positions = evicted.into_iter().map(|(_, i)| i).collect();
+        self.remove_positions(positions)
+    }
+
+    pub fn save_image_if_new(&mut self, image: Image<T>) -> Result<ImageVariant<T>, ImageDbError> {
+        self.save_image_if_new_ranked(image, 1).map(Into::into)
+    }
+
+    /// Saves image if there is nothing similar, otherwise returns up to `k` closest images within threshold.
+    /// Image that only matched as `MatchTier::SameTemplate` is a new meme, so it is saved as well
+    pub fn save_image_if_new_ranked(&mut self, image: Image<T>, k: usize) -> Result<MatchResult<T>, ImageDbError> {
+        self.evict_expired()?;
+        if let Err(rejection) = self.limits.check(&image.bytes) {
+            return Ok(MatchResult::Rejected(rejection));
+        }
+        let digest = compute_digest(&image.bytes);
+        let nearest = match self.find_by_digest(&digest) {
+            Some(i) => vec![Candidate::exact(i)],
+            None => {
+                let query = match self.query(&image) {
+                    Some(query) => query,
+                    None => return Ok(MatchResult::Undecodable),
+                };
+                let nearest = self.find_matches(&query, k.max(1));
+                let is_new_text = nearest.iter().all(|x| x.tier == MatchTier::SameTemplate);
+                if is_new_text {
+                    // Positions change when the new image pushes others over capacity
+                    let similar = self.to_similar_images(nearest);
+                    // Only the image as given is stored
+                    let (_, hash) = query.hashes.into_iter().next().unwrap();
+                    let cached = CachedHash::new(
+                        &self.hasher,
+                        hash,
+                        query.tiles,
+                        self.preprocessing,
+                        query.detail,
+                        query.frames,
+                    );
+                    self.insert_hashed(image, cached, digest)?;
+                    if similar.is_empty() {
+                        return Ok(MatchResult::New);
+                    }
+                    return Ok(MatchResult::AlreadyExists(similar));
+                }
+                nearest
+            }
+        };
+        let now = SystemTime::now();
+        for candidate in nearest.iter() {
+            self.images[candidate.position].last_matched = now;
+        }
+        Ok(MatchResult::AlreadyExists(self.to_similar_images(nearest)))
+    }
+
+    /// Looks for up to `k` closest images within threshold without saving anything
+    pub fn find_similar(&self, image: &Image<T>, k: usize) -> MatchResult<T> {
+        if let Err(rejection) = self.limits.check(&image.bytes) {
+            return MatchResult::Rejected(rejection);
+        }
+        if let Some(i) = self.find_by_digest(&compute_digest(&image.bytes)) {
+            return MatchResult::AlreadyExists(self.to_similar_images(vec![Candidate::exact(i)]));
+        }
+        let query = match self.query(image) {
+            Some(query) => query,
+            None => return MatchResult::Undecodable,
+        };
+        let nearest = self.find_matches(&query, k.max(1));
+        if nearest.is_empty() {
+            MatchResult::New
+        } else {
+            MatchResult::AlreadyExists(self.to_similar_images(nearest))
+        }
+    }
+
+    /// Saves image without checking whether it is already known
+    pub fn insert(&mut self, image: Image<T>) -> Result<(), ImageDbError> {
+        if let Err(rejection) = self.limits.check(&image.bytes) {
+            return Err(ImageDbError::RejectedImage(
+                image.metadata.file_name().to_string(),
+                rejection,
+            ));
+        }
+        match compute_cached_hash(&self.hasher, self.preprocessing, &self.limits, &image.bytes) {
+            Some(cached) => {
+                let digest = compute_digest(&image.bytes);
+                self.insert_hashed(image, cached, digest)
+            }
+            None => Err(ImageDbError::UndecodableImage(image.metadata.file_name().to_string())),
+        }
+    }
+
+    /// Returns `k` stored images closest to `image` regardless of threshold, closest first.
+    /// Undecodable or rejected image has no neighbours
+    pub fn find_nearest(&self, image: &Image<T>, k: usize) -> Vec<SimilarImage<T>> {
+        if self.limits.check(&image.bytes).is_err() {
+            return Vec::new();
+        }
+        match self.query(image) {
+            Some(query) => {
+                let nearest = self.find_nearest_transformed(&query, k, std::f64::INFINITY);
+                self.to_similar_images(self.classify(&query, nearest))
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes image with given file name from memory and storage, returns its metadata if it was known
+    pub fn remove(&mut self, file_name: &str) -> Result<Option<T>, ImageDbError> {
+        let mut removed = self.remove_where(|metadata| metadata.file_name() == file_name)?;
+        Ok(removed.pop())
+    }
+
+    /// Removes all images whose metadata matches `predicate`, returns metadata of removed images
+    pub fn remove_where<F: Fn(&T) -> bool>(&mut self, predicate: F) -> Result<Vec<T>, ImageDbError> {
+        self.remove_matching(|image| predicate(&image.metadata))
+    }
+
+    fn remove_matching<F: Fn(&KnownImage<T>) -> bool>(&mut self, predicate: F) -> Result<Vec<T>, ImageDbError> {
+        let positions = self
+            .images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| predicate(image))
+            .map(|(i, _)| i)
+            .collect();
+        self.remove_positions(positions)
+    }
+
+    /// Removes images at given positions from memory and storage, returns their metadata
+    fn remove_positions(&mut self, mut positions: Vec<usize>) -> Result<Vec<T>, ImageDbError> {
+        if positions.is_empty() {
+            return Ok(Vec::new());
+        }
+        // The last image takes place of the removed one, going from the end keeps the rest of positions valid
+        positions.sort_unstable_by(|a, b| b.cmp(a));
+        let removed: Vec<_> = positions.into_iter().map(|i| self.swap_remove(i)).collect();
+        if self.index.as_ref().map_or(false, BkTree::is_sparse) {
+            self.rebuild_index();
+        }
+        let mut result = Vec::with_capacity(removed.len());
+        for image in removed.into_iter().rev() {
+            self.database.remove_image(&image.metadata)?;
+            result.push(image.metadata);
+        }
+        Ok(result)
+    }
+
+    /// Removes image from memory, moving the last image to its position in BK-tree and digests
+    fn swap_remove(&mut self, position: usize) -> KnownImage<T> {
+        let last = self.images.len() - 1;
+        if let Some(index) = &mut self.index {
+            if let ImageHash::Binary(bits) = &self.images[position].hash {
+                index.remove(bits, position);
+            }
+            if position != last {
+                if let ImageHash::Binary(bits) = &self.images[last].hash {
+                    index.replace(bits, last, position);
+                }
+            }
+        }
+        if let Some(digest) = &self.images[position].digest {
+            if self.digests.get(digest) == Some(&position) {
+                self.digests.remove(digest);
+            }
+        }
+        if let Some(digest) = &self.images[last].digest {
+            if position != last && self.digests.get(digest) == Some(&last) {
+                self.digests.insert(digest.clone(), position);
+            }
+        }
+        self.images.swap_remove(position)
+    }
+
+    /// BK-tree and digests refer to images by position, they are built again after load and when BK-tree is sparse
+    fn rebuild_index(&mut self) {
+        self.digests = self
+            .images
+            .iter()
+            .enumerate()
+            .filter_map(|(i, image)| image.digest.clone().map(|digest| (digest, i)))
+            .collect();
+        self.index = if self.hasher.is_binary() {
+            let mut index = BkTree::new();
+            for (i, image) in self.images.iter().enumerate() {
+                if let ImageHash::Binary(bits) = &image.hash {
+                    index.insert(bits.clone(), i);
+                }
+            }
+            Some(index)
+        } else {
+            None
+        };
+    }
+
+    /// Prepares image and computes hashes of every its transform that should be matched
+    fn query(&self, image: &Image<T>) -> Option<Query> {
+        let min_side = min_decoded_side(&self.hasher, self.preprocessing, self.verification.is_some());
+        let (mat, crop, factor) = prepare_image(&image.bytes, self.preprocessing, min_side)?;
+        let transforms: &[Transform] = if self.transform_invariant {
+            &Transform::ALL
+        } else {
+            &[Transform::Identity]
+        };
+        let hashes = transforms
+            .iter()
+            .map(|&transform| match transform {
+                Transform::Identity => (transform, self.hasher.compute(&mat)),
+                _ => (transform, self.hasher.compute(&transform.apply(&mat))),
+            })
+            .collect();
+        let tiles = compute_tiles(&self.hasher, self.preprocessing, &mat, factor);
+        let detail = compute_detail_hash(&mat);
+        let frames = hash_keyframes(&self.hasher, &image.bytes, &self.limits);
+        Some(Query {
+            mat,
+            crop,
+            factor,
+            hashes,
+            tiles,
+            detail,
+            frames,
+        })
+    }
+
+    fn insert_hashed(&mut self, image: Image<T>, cached: CachedHash, digest: Vec<u8>) -> Result<(), ImageDbError> {
+        self.database.save_image(&image)?;
+        self.database.save_hash(&image.metadata, &cached)?;
+        self.database.save_digest(&image.metadata, &digest)?;
+        if let (Some(index), ImageHash::Binary(bits)) = (&mut self.index, &cached.hash) {
+            index.insert(bits.clone(), self.images.len());
+        }
+        self.digests.insert(digest.clone(), self.images.len());
+        self.images
+            .push(KnownImage::new(image.metadata, cached, Some(digest), SystemTime::now()));
+        self.evict_over_capacity()?;
+        Ok(())
+    }
+
+    /// Returns position of not expired image with exactly the same bytes
+    fn find_by_digest(&self, digest: &[u8]) -> Option<usize> {
+        let now = SystemTime::now();
+        self.digests
+            .get(digest)
+            .cloned()
+            .filter(|&i| !is_expired(self.retention, self.images[i].inserted_at, now))
+    }
+
+    fn to_similar_images(&self, nearest: Vec<Candidate>) -> Vec<SimilarImage<T>> {
+        nearest
             .into_iter()
-            .map(|image| {
-                let mat = Mat::image_decode(&image.bytes, ImageReadMode::Color);
-                let mat = hasher.compute(&mat);
-                (mat, image.metadata)
+            .map(|candidate| SimilarImage {
+                metadata: self.images[candidate.position].metadata.clone(),
+                distance: candidate.distance,
+                transform: candidate.transform,
+                crop: candidate.crop,
+                feature_matches: candidate.feature_matches,
+                tile: candidate.tile,
+                tier: candidate.tier,
             })
-            .collect::<Vec<_>>();
-        Self {
-            database,
-            hasher: hasher,
-            images: images,
+            .collect()
+    }
+
+    /// Returns up to `k` images within threshold, or matched by tiles or local features if there are none.
+    /// Animations and videos are only compared with each other, by keyframe sequences
+    fn find_matches(&self, query: &Query, k: usize) -> Vec<Candidate> {
+        if !query.frames.is_empty() {
+            return self.find_sequences(query, k);
+        }
+        let nearest = self.find_nearest_transformed(query, k, self.threshold);
+        if !nearest.is_empty() {
+            return self.classify(query, nearest);
+        }
+        let tiled = self.find_tiled(query, k);
+        if !tiled.is_empty() {
+            return self.classify(query, tiled);
+        }
+        let verification = match self.verification {
+            Some(verification) => verification,
+            None => return nearest,
+        };
+        let candidates = self.find_nearest_transformed(query, verification.candidates, std::f64::INFINITY);
+        if candidates.is_empty() {
+            return candidates;
+        }
+        let features = extract_features(&query.mat);
+        let mut confirmed = Vec::new();
+        for mut candidate in candidates {
+            let metadata = &self.images[candidate.position].metadata;
+            let stored = match self.database.load_image(metadata) {
+                Ok(Some(image)) => image,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(
+                        "Failed to load image {} for verification: {:?}",
+                        metadata.file_name(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            // Features are extracted from a downscaled copy anyway
+            let stored = match decode_image_reduced(&stored.bytes, Some(features::WORKING_SIZE)) {
+                Some((mat, _)) => mat,
+                None => continue,
+            };
+            let matches = count_consistent_matches(&features, &extract_features(&stored));
+            if matches >= verification.min_matches {
+                candidate.feature_matches = Some(matches);
+                confirmed.push(candidate);
+            }
         }
+        confirmed.truncate(k);
+        confirmed
     }
 
-    pub fn save_image_if_new(&mut self, image: Image<T>) -> ImageVariant<T> {
-        const DIFF: f64 = 1.0;
+    /// Returns up to `k` animations with keyframe sequences within threshold from the queried one, closest first
+    fn find_sequences(&self, query: &Query, k: usize) -> Vec<Candidate> {
+        let now = SystemTime::now();
+        let mut nearest: Vec<Candidate> = self
+            .images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| !image.frames.is_empty() && !is_expired(self.retention, image.inserted_at, now))
+            .map(|(position, image)| {
+                let distance = compare_sequences(&self.hasher, &query.frames, &image.frames);
+                Candidate::new(distance, position, query.crop)
+            })
+            .filter(|candidate| candidate.distance < self.threshold)
+            .collect();
+        nearest.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        nearest.truncate(k);
+        nearest
+    }
+
+    /// Sets tiers of candidates by comparing detail hashes of the matched parts.
+    /// Parts of stored collages and crops confirmed by features have nothing to compare with and stay re-encoded
+    fn classify(&self, query: &Query, mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+        for candidate in candidates.iter_mut() {
+            let stored = match self.images[candidate.position].detail {
+                Some(ref detail) => detail,
+                None => continue,
+            };
+            let detail = match (candidate.tile, candidate.transform) {
+                (None, Transform::Identity) => query.detail.clone(),
+                (None, transform) => compute_detail_hash(&transform.apply(&query.mat)),
+                (Some(TileMatch::Query(region)), _) => {
+                    let region = region.reduced(query.factor);
+                    compute_detail_hash(&query.mat.roi(region.to_rect()).clone())
+                }
+                (Some(TileMatch::Stored(_)), _) => continue,
+            };
+            candidate.tier = classify_by_details(&detail, stored);
+        }
+        candidates
+    }
+
+    /// Returns up to `k` images that are a part of queried collage or have it as a part, closest first.
+    /// Every image is reported once, with the closest tile
+    fn find_tiled(&self, query: &Query, k: usize) -> Vec<Candidate> {
+        let mut nearest: Vec<Candidate> = Vec::new();
+        for tile in query.tiles.iter() {
+            for (distance, position) in self.find_nearest_by_hash(&tile.hash, k, self.threshold) {
+                push_closest(
+                    &mut nearest,
+                    Candidate {
+                        tile: Some(TileMatch::Query(tile.region)),
+                        ..Candidate::new(distance, position, query.crop)
+                    },
+                );
+            }
+        }
+        let now = SystemTime::now();
+        let (_, hash) = &query.hashes[0];
+        for (position, image) in self.images.iter().enumerate() {
+            if is_expired(self.retention, image.inserted_at, now) {
+                continue;
+            }
+            for tile in image.tiles.iter() {
+                let distance = self.hasher.compare(hash, &tile.hash);
+                if distance < self.threshold {
+                    push_closest(
+                        &mut nearest,
+                        Candidate {
+                            tile: Some(TileMatch::Stored(tile.region)),
+                            ..Candidate::new(distance, position, query.crop)
+                        },
+                    );
+                }
+            }
+        }
+        nearest.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        nearest.truncate(k);
+        nearest
+    }
 
-        let mat = Mat::image_decode(&image.bytes, ImageReadMode::Color);
-        let mat = self.hasher.compute(&mat);
-        let mut last_diff = std::f64::INFINITY;
-        let mut result: Option<T> = None;
-        for &(ref image, ref metadata) in self.images.iter() {
-            let diff = self.hasher.compare(&mat, &image);
-            if diff < last_diff {
-                last_diff = diff;
-                result = Some(metadata.clone());
+    /// Returns up to `k` closest images with distance below `limit` to any of query hashes, closest first.
+    /// Every image is reported once, with transform it is the closest to
+    fn find_nearest_transformed(&self, query: &Query, k: usize, limit: f64) -> Vec<Candidate> {
+        let mut nearest: Vec<Candidate> = Vec::new();
+        for (transform, hash) in query.hashes.iter() {
+            for (distance, position) in self.find_nearest_by_hash(hash, k, limit) {
+                let candidate = Candidate {
+                    distance,
+                    position,
+                    transform: *transform,
+                    crop: query.crop,
+                    feature_matches: None,
+                    tile: None,
+                    tier: MatchTier::ReEncoded,
+                };
+                push_closest(&mut nearest, candidate);
             }
         }
-        if last_diff < DIFF {
-            return ImageVariant::AlreadyExists(result.unwrap());
+        nearest.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        nearest.truncate(k);
+        nearest
+    }
+
+    /// Returns distances and positions of up to `k` closest images with distance below `limit`, closest first
+    fn find_nearest_by_hash(&self, hash: &ImageHash, k: usize, limit: f64) -> Vec<(f64, usize)> {
+        let now = SystemTime::now();
+        if let (Some(index), ImageHash::Binary(bits)) = (&self.index, hash) {
+            if limit <= 0.0 {
+                return Vec::new();
+            }
+            // Hamming distance is integer, so `distance < limit` is the same as `distance <= ceil(limit) - 1`
+            let radius = limit.min(f64::from(std::u32::MAX)).ceil() as u32 - 1;
+            // Expired images stay in the tree until eviction, so every candidate is needed to skip them
+            let candidates = if self.retention.is_some() { self.images.len() } else { k };
+            return index
+                .find_nearest(bits, candidates, radius)
+                .into_iter()
+                .filter(|&(_, i)| !is_expired(self.retention, self.images[i].inserted_at, now))
+                .take(k)
+                .map(|(distance, i)| (f64::from(distance), i))
+                .collect();
         }
-        self.database.save_image(&image);
-        self.images.push((mat, image.metadata));
-        ImageVariant::New
+        let mut distances = self
+            .images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| !is_expired(self.retention, image.inserted_at, now))
+            .map(|(i, image)| (self.hasher.compare(hash, &image.hash), i))
+            .filter(|&(distance, _)| distance < limit)
+            .collect::<Vec<_>>();
+        distances.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        distances.truncate(k);
+        distances
     }
 
     pub fn image_count(&self) -> usize {
@@ -119,45 +1136,332 @@ impl<T: Metadata, D: Storage<T>> ImageDb<T, D> {
     }
 }
 
+fn compute_digest(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+/// Adds candidate unless the same image is already there with smaller distance
+fn push_closest(candidates: &mut Vec<Candidate>, candidate: Candidate) {
+    match candidates.iter_mut().find(|x| x.position == candidate.position) {
+        Some(ref known) if known.distance <= candidate.distance => {}
+        Some(known) => *known = candidate,
+        None => candidates.push(candidate),
+    }
+}
+
+/// Decodes image, computes all its hashes and tags them. Needs no database, so images could be hashed in parallel
+fn compute_cached_hash<H: PerceptualHasher>(
+    hasher: &H,
+    preprocessing: Preprocessing,
+    limits: &ImageLimits,
+    bytes: &[u8],
+) -> Option<CachedHash> {
+    let min_side = min_decoded_side(hasher, preprocessing, false);
+    let (mat, _, factor) = prepare_image(bytes, preprocessing, min_side)?;
+    let hash = hasher.compute(&mat);
+    let tiles = compute_tiles(hasher, preprocessing, &mat, factor);
+    let detail = compute_detail_hash(&mat);
+    let frames = hash_keyframes(hasher, bytes, limits);
+    Some(CachedHash::new(hasher, hash, tiles, preprocessing, detail, frames))
+}
+
+/// Decodes image, possibly at reduced resolution, and trims it if needed.
+/// Returns reduction factor along with the image, crop is in pixels of the original image
+fn prepare_image(
+    bytes: &[u8],
+    preprocessing: Preprocessing,
+    min_side: Option<i32>,
+) -> Option<(Mat, Option<Crop>, i32)> {
+    let (mat, factor) = decode_image_reduced(bytes, min_side)?;
+    if !preprocessing.trim_borders {
+        return Some((mat, None, factor));
+    }
+    match find_content(&mat) {
+        // Region of interest shares data with the whole image, clone makes it continuous
+        Some(crop) => Some((mat.roi(crop.to_rect()).clone(), Some(crop.scaled(factor)), factor)),
+        None => Some((mat, None, factor)),
+    }
+}
+
+/// Smallest side images could be decoded with, so that nothing computed from them loses accuracy.
+/// `None` if the hasher needs full resolution
+fn min_decoded_side<H: PerceptualHasher>(hasher: &H, preprocessing: Preprocessing, verification: bool) -> Option<i32> {
+    let mut side = hasher.working_size()?.max(tier::MIN_DETAIL_SIDE);
+    // Trimmed content and tiles of it are hashed at working size too, they are only this share of the image side
+    let mut share = 1.0;
+    if preprocessing.trim_borders {
+        share *= trim::MIN_CONTENT_SHARE;
+    }
+    if preprocessing.tiles {
+        share *= trim::MIN_PANEL_SHARE;
+    }
+    side = (f64::from(side) / share).ceil() as i32;
+    if verification {
+        side = side.max(features::WORKING_SIZE);
+    }
+    Some(side)
+}
+
+/// Regions of tiles are scaled by `factor` to pixels of the original image
+fn compute_tiles<H: PerceptualHasher>(
+    hasher: &H,
+    preprocessing: Preprocessing,
+    mat: &Mat,
+    factor: i32,
+) -> Vec<TileHash> {
+    if !preprocessing.tiles {
+        return Vec::new();
+    }
+    find_tiles(mat)
+        .into_iter()
+        .map(|region| TileHash {
+            region: region.scaled(factor),
+            hash: hasher.compute(&mat.roi(region.to_rect()).clone()),
+        })
+        .collect()
+}
+
+fn is_expired(retention: Option<Duration>, inserted_at: SystemTime, now: SystemTime) -> bool {
+    match (retention, now.duration_since(inserted_at)) {
+        (Some(retention), Ok(age)) => age > retention,
+        _ => false,
+    }
+}
+
+/// What `FileStorage` keeps on disk for every image besides its metadata and hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Original image bytes
+    Full,
+    /// Nothing, optionally a small JPEG copy which is used to rehash the image when hash algorithm changes.
+    ///
+    /// Without thumbnail images with stale hashes can't be rehashed and are dropped on load.
+    HashOnly { keep_thumbnail: bool },
+}
+
 pub struct FileStorage<T> {
     path: PathBuf,
+    mode: StorageMode,
     marker_: PhantomData<T>,
 }
 
 impl<T> FileStorage<T> {
     pub fn new(path: PathBuf) -> Self {
+        Self::with_mode(path, StorageMode::Full)
+    }
+
+    pub fn with_mode(path: PathBuf, mode: StorageMode) -> Self {
         Self {
-            path: path,
+            path,
+            mode,
             marker_: PhantomData,
         }
     }
 }
 
 impl<T: Metadata + serde::Serialize + serde::de::DeserializeOwned> Storage<T> for FileStorage<T> {
-    fn save_image(&mut self, image: &Image<T>) {
+    fn save_image(&mut self, image: &Image<T>) -> Result<(), ImageDbError> {
         let path = self.path.join(image.metadata.file_name());
 
-        let mut binary_file = File::create(&path).unwrap();
-        binary_file.write_all(&image.bytes).unwrap();
+        match self.mode {
+            StorageMode::Full => {
+                let mut binary_file = File::create(&path)?;
+                binary_file.write_all(&image.bytes)?;
+            }
+            StorageMode::HashOnly { keep_thumbnail: true } => {
+                if let Some(thumbnail) = make_thumbnail(&image.bytes) {
+                    let mut binary_file = File::create(path.with_extension(THUMBNAIL_EXTENSION))?;
+                    binary_file.write_all(&thumbnail)?;
+                }
+            }
+            StorageMode::HashOnly { keep_thumbnail: false } => {}
+        }
+
+        write_timestamp(&path.with_extension(TIMESTAMP_EXTENSION), SystemTime::now())?;
+        let json_file = File::create(path.with_extension(METADATA_EXTENSION))?;
+        serde_json::to_writer(json_file, &image.metadata)?;
+        Ok(())
+    }
 
-        let json_file = File::create(path.with_extension("json")).unwrap();
-        serde_json::to_writer(json_file, &image.metadata).unwrap();
+    fn load_images(&self) -> Result<Vec<Image<T>>, ImageDbError> {
+        let mut images = Vec::new();
+        for entry in self.load_entries()? {
+            if let Some(image) = self.load_image(&entry.metadata)? {
+                images.push(image);
+            }
+        }
+        Ok(images)
     }
 
-    fn load_images(&self) -> Vec<Image<T>> {
-        let entries = fs::read_dir(&self.path).unwrap();
-        entries
-            .map(|e| e.unwrap().path())
-            .filter(|e| e.extension().and_then(|x| x.to_str()) != Some("json"))
-            .map(|path| {
-                let mut binary_reader = File::open(&path).unwrap();
-                let mut bytes = Vec::with_capacity(1000000);
-                binary_reader.read_to_end(&mut bytes).unwrap();
+    fn save_hash(&mut self, metadata: &T, hash: &CachedHash) -> Result<(), ImageDbError> {
+        let path = self.path.join(metadata.file_name()).with_extension(HASH_EXTENSION);
+        let hash_file = File::create(path)?;
+        serde_json::to_writer(hash_file, hash)?;
+        Ok(())
+    }
 
-                let reader = File::open(path.with_extension("json")).unwrap();
-                let metadata: T = serde_json::from_reader(reader).unwrap();
-                Image::new(bytes, metadata)
-            })
-            .collect()
+    fn save_digest(&mut self, metadata: &T, digest: &[u8]) -> Result<(), ImageDbError> {
+        let path = self.path.join(metadata.file_name()).with_extension(DIGEST_EXTENSION);
+        fs::write(path, digest)?;
+        Ok(())
+    }
+
+    fn load_entries(&self) -> Result<Vec<StoredEntry<T>>, ImageDbError> {
+        let mut entries = Vec::new();
+        for path in self.metadata_paths()? {
+            // One corrupt file should not take down the whole database
+            let metadata = match read_json(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("Metadata {} could not be read, skipping: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+            entries.push(StoredEntry {
+                metadata,
+                // Broken cache is no different from missing one, image is just rehashed
+                hash: read_json(&path.with_extension(HASH_EXTENSION)).ok(),
+                // Images saved before digests were introduced are only matched by hash
+                digest: fs::read(path.with_extension(DIGEST_EXTENSION)).ok(),
+                inserted_at: self.read_inserted_at(&path)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn load_image(&self, metadata: &T) -> Result<Option<Image<T>>, ImageDbError> {
+        let path = self.path.join(metadata.file_name());
+        let path = match self.mode {
+            StorageMode::Full => path,
+            StorageMode::HashOnly { keep_thumbnail: true } => path.with_extension(THUMBNAIL_EXTENSION),
+            StorageMode::HashOnly { keep_thumbnail: false } => return Ok(None),
+        };
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(Image::new(bytes, metadata.clone()))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove_image(&mut self, metadata: &T) -> Result<(), ImageDbError> {
+        let path = self.path.join(metadata.file_name());
+        // Metadata goes first: without it the rest is never loaded again even if removal fails halfway
+        remove_file_if_exists(&path.with_extension(METADATA_EXTENSION))?;
+        remove_file_if_exists(&path.with_extension(HASH_EXTENSION))?;
+        remove_file_if_exists(&path.with_extension(DIGEST_EXTENSION))?;
+        remove_file_if_exists(&path.with_extension(TIMESTAMP_EXTENSION))?;
+        remove_file_if_exists(&path.with_extension(THUMBNAIL_EXTENSION))?;
+        remove_file_if_exists(&path)
+    }
+}
+
+impl<T> FileStorage<T> {
+    /// Every saved image has a metadata file regardless of storage mode
+    fn metadata_paths(&self) -> Result<Vec<PathBuf>, ImageDbError> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) == Some(METADATA_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Time is saved explicitly, since modification times change when storage directory is copied or restored.
+    /// Images saved before that get modification time of their metadata file, which is saved for the next load
+    fn read_inserted_at(&self, metadata_path: &Path) -> Result<SystemTime, ImageDbError> {
+        let path = metadata_path.with_extension(TIMESTAMP_EXTENSION);
+        if let Some(inserted_at) = read_timestamp(&path) {
+            return Ok(inserted_at);
+        }
+        let inserted_at = fs::metadata(metadata_path)?.modified()?;
+        // Loading must not fail on a read-only or full disk, time is saved again on the next load
+        if let Err(e) = write_timestamp(&path, inserted_at) {
+            warn!("Time of {} could not be saved: {:?}", metadata_path.display(), e);
+        }
+        Ok(inserted_at)
+    }
+}
+
+/// Timestamps are stored as milliseconds since Unix epoch in decimal
+fn write_timestamp(path: &Path, time: SystemTime) -> Result<(), ImageDbError> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let millis = since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis());
+    fs::write(path, millis.to_string())?;
+    Ok(())
+}
+
+fn read_timestamp(path: &Path) -> Option<SystemTime> {
+    let millis: u64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Downscales image so its longest side is at most `THUMBNAIL_SIZE` and encodes it as JPEG
+fn make_thumbnail(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mat = decode_image(bytes)?;
+    let scale = f64::from(THUMBNAIL_SIZE) / f64::from(mat.rows.max(mat.cols));
+    let thumbnail = if scale < 1.0 {
+        let width = ((f64::from(mat.cols) * scale).round() as i32).max(1);
+        let height = ((f64::from(mat.rows) * scale).round() as i32).max(1);
+        mat.resize_to(Size2i::new(width, height), InterpolationFlag::InterArea)
+    } else {
+        mat
+    };
+    thumbnail.image_encode(".jpg", Vec::new()).ok()
+}
+
+/// Decodes image at the smallest resolution with both sides at least `min_side`, returns it with reduction factor.
+///
+/// Only JPEG is decoded reduced: libjpeg scales DCT blocks while decoding, other formats are decoded fully anyway.
+fn decode_image_reduced(bytes: &[u8], min_side: Option<i32>) -> Option<(Mat, i32)> {
+    let dimensions = match (min_side, ImageFormat::detect(bytes)) {
+        (Some(_), Some(ImageFormat::Jpeg)) => read_dimensions(bytes),
+        _ => None,
+    };
+    if let (Some(min_side), Some(dimensions)) = (min_side, dimensions) {
+        let shorter = dimensions.width.min(dimensions.height) as i32;
+        let modes = [
+            (8, ImageReadMode::ReducedColor8),
+            (4, ImageReadMode::ReducedColor4),
+            (2, ImageReadMode::ReducedColor2),
+        ];
+        if let Some(&(factor, mode)) = modes.iter().find(|&&(factor, _)| shorter / factor >= min_side) {
+            let mat = Mat::image_decode(bytes, mode);
+            if mat.is_valid() && mat.rows > 0 && mat.cols > 0 {
+                return Some((mat, factor));
+            }
+        }
     }
+    decode_image(bytes).map(|mat| (mat, 1))
+}
+
+/// Decodes image as BGR, returns `None` for unknown formats or if OpenCV produced an empty matrix
+fn decode_image(bytes: &[u8]) -> Option<Mat> {
+    match ImageFormat::detect(bytes) {
+        Some(ImageFormat::Gif) => return decode_first_frame(bytes),
+        Some(format) if format.is_video() => return decode_first_frame(bytes),
+        // Size of unknown formats can't be checked before decoding, so they are not decoded at all
+        None => return None,
+        _ => {}
+    }
+    let mat = Mat::image_decode(bytes, ImageReadMode::Color);
+    if mat.is_valid() && mat.rows > 0 && mat.cols > 0 {
+        Some(mat)
+    } else {
+        None
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<(), ImageDbError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_json<V: serde::de::DeserializeOwned>(path: &Path) -> Result<V, ImageDbError> {
+    let reader = File::open(path)?;
+    Ok(serde_json::from_reader(reader)?)
 }
@@ -0,0 +1,338 @@
+use crate::format::ImageFormat;
+use crate::hasher::{ImageHash, PerceptualHasher};
+use crate::header::{Dimensions, ImageLimits};
+use cv::imgproc::*;
+use cv::videoio::*;
+use cv::*;
+use gif::{DisposalMethod, SetParameter};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keyframes are taken at this interval, in seconds, so clips with different frame rates give the same sequence
+const KEYFRAME_INTERVAL: f64 = 0.5;
+const MAX_KEYFRAMES: usize = 64;
+/// Long videos are not read to the end, only their beginning is compared
+const MAX_DECODED_FRAMES: usize = 3000;
+/// Trimmed clip should still overlap with the other one by this share of the shorter sequence
+const MIN_OVERLAP_SHARE: f64 = 0.5;
+const MIN_OVERLAP: usize = 2;
+/// Used when GIF frame has no delay or video has no frame rate
+const DEFAULT_FRAME_DURATION: f64 = 0.1;
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Decodes first frame of animated GIF or video
+pub fn decode_first_frame(bytes: &[u8]) -> Option<Mat> {
+    match ImageFormat::detect(bytes) {
+        Some(ImageFormat::Gif) => first_frame(GifFrames::new(bytes)?),
+        Some(format) if format.is_video() => first_frame(VideoFrames::new(bytes, format)?),
+        _ => None,
+    }
+}
+
+/// Reads frame size of a video from its container without decoding any frame
+pub fn read_video_dimensions(bytes: &[u8]) -> Option<Dimensions> {
+    match ImageFormat::detect(bytes)? {
+        format if format.is_video() => VideoFrames::new(bytes, format).map(|frames| frames.dimensions),
+        _ => None,
+    }
+}
+
+/// Hashes frames of animated GIF or video taken every `KEYFRAME_INTERVAL` seconds.
+/// Decoding stops once frames decoded so far take `limits.max_decoded_pixels`.
+/// Returns nothing for still images and single frame animations
+pub fn hash_keyframes<H: PerceptualHasher + ?Sized>(hasher: &H, bytes: &[u8], limits: &ImageLimits) -> Vec<ImageHash> {
+    let max_pixels = limits.max_decoded_pixels;
+    let hashes = match ImageFormat::detect(bytes) {
+        Some(ImageFormat::Gif) => GifFrames::new(bytes).map(|frames| sample_keyframes(frames, hasher, max_pixels)),
+        Some(format) if format.is_video() => {
+            VideoFrames::new(bytes, format).map(|frames| sample_keyframes(frames, hasher, max_pixels))
+        }
+        _ => None,
+    }
+    .unwrap_or_default();
+    if hashes.len() < 2 {
+        Vec::new()
+    } else {
+        hashes
+    }
+}
+
+/// Returns mean distance between aligned keyframes for the best alignment of two sequences.
+///
+/// Sequences are shifted against each other, so a clip trimmed at either end is still close to the original one.
+pub fn compare_sequences<H: PerceptualHasher + ?Sized>(hasher: &H, query: &[ImageHash], stored: &[ImageHash]) -> f64 {
+    let shorter = query.len().min(stored.len());
+    let min_overlap = ((shorter as f64 * MIN_OVERLAP_SHARE).ceil() as usize).max(MIN_OVERLAP);
+    if shorter < min_overlap {
+        return std::f64::INFINITY;
+    }
+    let mut best = std::f64::INFINITY;
+    for shift in 0..query.len() + stored.len() - 1 {
+        // `offset` is position of the first query frame in the stored sequence
+        let offset = shift as isize - (query.len() as isize - 1);
+        let pairs: Vec<(&ImageHash, &ImageHash)> = query
+            .iter()
+            .enumerate()
+            .filter_map(|(i, hash)| {
+                let j = i as isize + offset;
+                if j >= 0 && (j as usize) < stored.len() {
+                    Some((hash, &stored[j as usize]))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if pairs.len() < min_overlap {
+            continue;
+        }
+        let total: f64 = pairs.iter().map(|&(a, b)| hasher.compare(a, b)).sum();
+        best = best.min(total / pairs.len() as f64);
+    }
+    best
+}
+
+/// Decoded frames of an animation. Only the last decoded frame is kept
+trait Frames {
+    /// Decodes the next frame, returns time it is shown at in seconds
+    fn advance(&mut self) -> Option<f64>;
+
+    /// Returns copy of the last decoded frame, downscaled so that its shorter side is `min_side` if it is larger.
+    /// `None` until the first frame is decoded
+    fn current(&self, min_side: Option<i32>) -> Option<Mat>;
+
+    /// Every frame is decoded at this size, however little of it has changed
+    fn dimensions(&self) -> Dimensions;
+}
+
+fn first_frame<F: Frames>(mut frames: F) -> Option<Mat> {
+    frames.advance()?;
+    frames.current(None)
+}
+
+/// Hashes the first frame shown at or after every `KEYFRAME_INTERVAL`.
+/// Keyframes are downscaled to working size of the hasher and hashed right after they are decoded,
+/// so only hashes are kept however long the animation is
+fn sample_keyframes<F: Frames, H: PerceptualHasher + ?Sized>(
+    mut frames: F,
+    hasher: &H,
+    max_pixels: u64,
+) -> Vec<ImageHash> {
+    let mut hashes = Vec::new();
+    let mut decoded_pixels = 0;
+    for _ in 0..MAX_DECODED_FRAMES {
+        decoded_pixels += frames.dimensions().pixels();
+        if decoded_pixels > max_pixels {
+            break;
+        }
+        let time = match frames.advance() {
+            Some(time) => time,
+            None => break,
+        };
+        if time < hashes.len() as f64 * KEYFRAME_INTERVAL {
+            continue;
+        }
+        if let Some(frame) = frames.current(hasher.working_size()) {
+            hashes.push(hasher.compute(&frame));
+            if hashes.len() == MAX_KEYFRAMES {
+                break;
+            }
+        }
+    }
+    hashes
+}
+
+fn downscale(frame: &Mat, min_side: Option<i32>) -> Mat {
+    let min_side = match min_side {
+        Some(min_side) if min_side < frame.rows.min(frame.cols) => min_side,
+        _ => return frame.clone(),
+    };
+    let scale = f64::from(min_side) / f64::from(frame.rows.min(frame.cols));
+    let width = ((f64::from(frame.cols) * scale).ceil() as i32).max(min_side);
+    let height = ((f64::from(frame.rows) * scale).ceil() as i32).max(min_side);
+    frame.resize_to(Size2i::new(width, height), InterpolationFlag::InterArea)
+}
+
+/// Frames of GIF drawn over each other the way viewers show them. Transparent pixels of the canvas are white
+struct GifFrames<'a> {
+    reader: gif::Reader<&'a [u8]>,
+    width: usize,
+    height: usize,
+    canvas: Vec<u8>,
+    /// Applied to the canvas before the next frame is drawn, the last frame is shown until then
+    dispose: Disposal,
+    time: f64,
+}
+
+enum Disposal {
+    Keep,
+    /// Offsets of pixels drawn by the frame, they are cleared to background
+    Clear(Vec<usize>),
+    /// Canvas as it was before the frame was drawn
+    Restore(Vec<u8>),
+}
+
+impl<'a> GifFrames<'a> {
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        let mut decoder = gif::Decoder::new(bytes);
+        decoder.set(gif::ColorOutput::RGBA);
+        let reader = decoder.read_info().ok()?;
+        let width = usize::from(reader.width());
+        let height = usize::from(reader.height());
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(Self {
+            reader,
+            width,
+            height,
+            canvas: vec![255; width * height * 3],
+            dispose: Disposal::Keep,
+            time: 0.0,
+        })
+    }
+}
+
+impl<'a> Frames for GifFrames<'a> {
+    fn advance(&mut self) -> Option<f64> {
+        let (width, height) = (self.width, self.height);
+        match std::mem::replace(&mut self.dispose, Disposal::Keep) {
+            Disposal::Keep => {}
+            Disposal::Clear(drawn) => {
+                for offset in drawn {
+                    self.canvas[offset..offset + 3].copy_from_slice(&[255; 3]);
+                }
+            }
+            Disposal::Restore(previous) => self.canvas = previous,
+        }
+        let frame = self.reader.read_next_frame().ok()??;
+        let previous = match frame.dispose {
+            DisposalMethod::Previous => Some(self.canvas.clone()),
+            _ => None,
+        };
+        let (left, top) = (usize::from(frame.left), usize::from(frame.top));
+        let frame_width = usize::from(frame.width);
+        let mut drawn = Vec::new();
+        for (i, rgba) in frame.buffer.chunks(4).enumerate() {
+            let (x, y) = (left + i % frame_width, top + i / frame_width);
+            if x >= width || y >= height {
+                continue;
+            }
+            let offset = (y * width + x) * 3;
+            drawn.push(offset);
+            if rgba[3] != 0 {
+                self.canvas[offset..offset + 3].copy_from_slice(&[rgba[2], rgba[1], rgba[0]]);
+            }
+        }
+        let time = self.time;
+        self.time += match frame.delay {
+            0 => DEFAULT_FRAME_DURATION,
+            delay => f64::from(delay) / 100.0,
+        };
+        self.dispose = match (frame.dispose, previous) {
+            (_, Some(previous)) => Disposal::Restore(previous),
+            (DisposalMethod::Background, _) => Disposal::Clear(drawn),
+            _ => Disposal::Keep,
+        };
+        Some(time)
+    }
+
+    fn current(&self, min_side: Option<i32>) -> Option<Mat> {
+        // Matrix created from buffer borrows it, downscaling or cloning copies it before the canvas changes
+        let mat = Mat::from_buffer(self.height as i32, self.width as i32, CvType::Cv8UC3, &self.canvas);
+        Some(downscale(&mat, min_side))
+    }
+
+    fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            width: self.width as u32,
+            height: self.height as u32,
+        }
+    }
+}
+
+/// Frames of a video. `VideoCapture` reads only files, so bytes are written to a temporary one
+struct VideoFrames {
+    capture: VideoCapture,
+    path: PathBuf,
+    dimensions: Dimensions,
+    frame: Option<Mat>,
+    frame_duration: f64,
+    index: usize,
+}
+
+impl VideoFrames {
+    fn new(bytes: &[u8], format: ImageFormat) -> Option<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "imagedb_{}_{}.{}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst),
+            format.extension()
+        ));
+        // Path is predictable, so the file must be a new one rather than whatever is already there, e.g. a symlink
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path).ok()?;
+        if file.write_all(bytes).is_err() {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        drop(file);
+        // Temporary file is removed on drop, so the struct is created before anything else may fail
+        let mut frames = Self {
+            capture: VideoCapture::from_path(&path.to_string_lossy()),
+            dimensions: Dimensions { width: 0, height: 0 },
+            frame: None,
+            frame_duration: DEFAULT_FRAME_DURATION,
+            path,
+            index: 0,
+        };
+        if !frames.capture.is_open() {
+            return None;
+        }
+        let width = frames.capture.get(CapProp::FrameWidth).unwrap_or_default();
+        let height = frames.capture.get(CapProp::FrameHeight).unwrap_or_default();
+        if width < 1.0 || height < 1.0 {
+            return None;
+        }
+        frames.dimensions = Dimensions {
+            width: width as u32,
+            height: height as u32,
+        };
+        if let Some(fps) = frames.capture.get(CapProp::Fps) {
+            if fps > 0.0 {
+                frames.frame_duration = 1.0 / fps;
+            }
+        }
+        Some(frames)
+    }
+}
+
+impl Frames for VideoFrames {
+    fn advance(&mut self) -> Option<f64> {
+        // Previous frame is dropped first, so two of them never take memory at once
+        self.frame = None;
+        let frame = self.capture.read()?;
+        if !frame.is_valid() || frame.rows == 0 || frame.cols == 0 {
+            return None;
+        }
+        self.frame = Some(frame);
+        let time = self.index as f64 * self.frame_duration;
+        self.index += 1;
+        Some(time)
+    }
+
+    fn current(&self, min_side: Option<i32>) -> Option<Mat> {
+        self.frame.as_ref().map(|frame| downscale(frame, min_side))
+    }
+
+    fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+}
+
+impl Drop for VideoFrames {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
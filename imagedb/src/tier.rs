@@ -0,0 +1,72 @@
+use crate::hasher::ImageHash;
+use cv::imgproc::*;
+use cv::*;
+
+/// Detail hash is a grid of `GRID_SIZE` x `GRID_SIZE` cells, each one is a 64 bit difference hash
+const GRID_SIZE: i32 = 8;
+const CELL_WIDTH: i32 = 9;
+const CELL_HEIGHT: i32 = 8;
+const CELL_BYTES: usize = 8;
+/// Images are resized to this width before computing detail hash
+pub(crate) const MIN_DETAIL_SIDE: i32 = GRID_SIZE * CELL_WIDTH;
+/// Cell with more differing bits has different content, usually new caption text
+const MAX_CELL_DISTANCE: u32 = 16;
+/// Recompression and resizing may change a cell or two, more changed cells mean the text was replaced
+const MAX_CHANGED_CELLS: usize = 2;
+
+/// How close a matched image is to the stored one, from the closest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchTier {
+    /// Byte-identical copy
+    Identical,
+    /// The same picture saved again, resized or recompressed
+    ReEncoded,
+    /// The same picture with some regions replaced, e.g. meme template with another caption
+    SameTemplate,
+}
+
+/// Computes hash that keeps local details, so images that differ in small regions such as captions have different ones
+pub fn compute_detail_hash(image: &Mat) -> ImageHash {
+    let gray = image.cvt_color(ColorConversion::BGR2GRAY);
+    let width = GRID_SIZE * CELL_WIDTH;
+    let height = GRID_SIZE * CELL_HEIGHT;
+    let thumbnail = gray.resize_to(Size2i::new(width, height), InterpolationFlag::InterArea);
+    let pixels = thumbnail.data();
+    let (width, grid_size) = (width as usize, GRID_SIZE as usize);
+    let (cell_width, cell_height) = (CELL_WIDTH as usize, CELL_HEIGHT as usize);
+    let mut bits = vec![0u8; grid_size * grid_size * CELL_BYTES];
+    for (cell, cell_bits) in bits.chunks_mut(CELL_BYTES).enumerate() {
+        let (left, top) = ((cell % grid_size) * cell_width, (cell / grid_size) * cell_height);
+        for (row, byte) in cell_bits.iter_mut().enumerate() {
+            for col in 0..cell_width - 1 {
+                let offset = (top + row) * width + left + col;
+                if pixels[offset] < pixels[offset + 1] {
+                    *byte |= 1 << col;
+                }
+            }
+        }
+    }
+    ImageHash::Binary(bits)
+}
+
+/// Tells re-encoded copy from the same template by the number of changed cells of their detail hashes.
+/// Images with bytes that are not identical are never `MatchTier::Identical`
+pub fn classify_by_details(query: &ImageHash, stored: &ImageHash) -> MatchTier {
+    let (query, stored) = match (query, stored) {
+        (ImageHash::Binary(a), ImageHash::Binary(b)) if a.len() == b.len() => (a, b),
+        _ => return MatchTier::ReEncoded,
+    };
+    let changed_cells = query
+        .chunks(CELL_BYTES)
+        .zip(stored.chunks(CELL_BYTES))
+        .filter(|(a, b)| {
+            let distance: u32 = a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum();
+            distance > MAX_CELL_DISTANCE
+        })
+        .count();
+    if changed_cells > MAX_CHANGED_CELLS {
+        MatchTier::SameTemplate
+    } else {
+        MatchTier::ReEncoded
+    }
+}
@@ -0,0 +1,52 @@
+use crate::hasher::ImageHash;
+use crate::trim::{find_panels, Crop};
+use cv::*;
+use serde_derive::{Deserialize, Serialize};
+
+/// Rows and columns of grids collages are usually made of
+const GRIDS: [(i32, i32); 3] = [(1, 2), (2, 1), (2, 2)];
+/// Smaller images are not split, their tiles are too small to be hashed reliably
+const MIN_TILED_SIDE: i32 = 64;
+
+/// Hash of a part of an image. Region is in pixels of the original image, even if it was decoded reduced
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileHash {
+    pub region: Crop,
+    pub hash: ImageHash,
+}
+
+/// Part of a collage that matched, in pixels of the original image relative to its hashed part
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileMatch {
+    /// Region of the queried image is similar to the whole stored image
+    Query(Crop),
+    /// The whole queried image is similar to region of the stored image
+    Stored(Crop),
+}
+
+/// Returns regions that may be separate pictures of a collage: cells of common grids and detected panels
+pub fn find_tiles(image: &Mat) -> Vec<Crop> {
+    if image.rows < MIN_TILED_SIDE || image.cols < MIN_TILED_SIDE {
+        return Vec::new();
+    }
+    let mut tiles = Vec::new();
+    for &(rows, cols) in GRIDS.iter() {
+        let (height, width) = (image.rows / rows, image.cols / cols);
+        for row in 0..rows {
+            for col in 0..cols {
+                tiles.push(Crop {
+                    x: col * width,
+                    y: row * height,
+                    width,
+                    height,
+                });
+            }
+        }
+    }
+    for panel in find_panels(image) {
+        if !tiles.contains(&panel) {
+            tiles.push(panel);
+        }
+    }
+    tiles
+}
@@ -0,0 +1,79 @@
+use cv::*;
+
+/// One of eight rotations and reflections of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transform {
+    Identity,
+    /// Rotation by 90 degrees clockwise
+    Rotate90,
+    Rotate180,
+    /// Rotation by 90 degrees counterclockwise
+    Rotate270,
+    /// Mirror image, left and right sides swapped
+    FlipHorizontal,
+    /// Upside down mirror image
+    FlipVertical,
+    /// Reflection over the main diagonal
+    Transpose,
+    /// Reflection over the secondary diagonal
+    Transverse,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 8] = [
+        Transform::Identity,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    /// Returns transformed copy of `image`
+    pub fn apply(self, image: &Mat) -> Mat {
+        let (transpose_first, flip) = match self {
+            Transform::Identity => (false, None),
+            Transform::Rotate90 => (true, Some(FlipCode::YAxis)),
+            Transform::Rotate180 => (false, Some(FlipCode::XYAxis)),
+            Transform::Rotate270 => (true, Some(FlipCode::XAxis)),
+            Transform::FlipHorizontal => (false, Some(FlipCode::YAxis)),
+            Transform::FlipVertical => (false, Some(FlipCode::XAxis)),
+            Transform::Transpose => (true, None),
+            Transform::Transverse => (true, Some(FlipCode::XYAxis)),
+        };
+        let mut result = if transpose_first {
+            transpose(image)
+        } else {
+            image.clone()
+        };
+        if let Some(flip) = flip {
+            result.flip(flip);
+        }
+        result
+    }
+}
+
+/// `cv` has no transpose, so pixels of continuous 8-bit image are shuffled manually
+fn transpose(image: &Mat) -> Mat {
+    let rows = image.rows as usize;
+    let cols = image.cols as usize;
+    let channels = image.channels as usize;
+    let data = image.data();
+    let mut transposed = vec![0u8; rows * cols * channels];
+    for row in 0..rows {
+        for col in 0..cols {
+            let from = (row * cols + col) * channels;
+            let to = (col * rows + row) * channels;
+            transposed[to..to + channels].copy_from_slice(&data[from..from + channels]);
+        }
+    }
+    let cv_type = match channels {
+        1 => CvType::Cv8UC1,
+        4 => CvType::Cv8UC4,
+        _ => CvType::Cv8UC3,
+    };
+    // Matrix created from buffer borrows it, so it is cloned before the buffer is dropped
+    Mat::from_buffer(image.cols, image.rows, cv_type, &transposed).clone()
+}
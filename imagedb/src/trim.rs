@@ -0,0 +1,158 @@
+use cv::imgproc::*;
+use cv::*;
+use serde_derive::{Deserialize, Serialize};
+
+/// Lines with smaller standard deviation of brightness are considered a part of border or background
+const UNIFORM_STD_DEV: f64 = 6.0;
+/// Content smaller than this share of the image is more likely a caption than the picture itself
+const MIN_CONTENT_AREA: f64 = 0.25;
+/// Either side of content is at least that share of the image side, since the other one can't be larger than image
+pub(crate) const MIN_CONTENT_SHARE: f64 = MIN_CONTENT_AREA;
+const MIN_CONTENT_SIDE: usize = 16;
+/// Collage panels narrower than this share of the image are more likely captions or decorations
+pub(crate) const MIN_PANEL_SHARE: f64 = 0.2;
+
+/// Rectangular part of image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Crop {
+    pub fn to_rect(self) -> Rect {
+        Rect::new(self.x, self.y, self.width, self.height)
+    }
+
+    /// Region of the same part of image `factor` times larger
+    pub fn scaled(self, factor: i32) -> Self {
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+            width: self.width * factor,
+            height: self.height * factor,
+        }
+    }
+
+    /// Region of the same part of image `factor` times smaller
+    pub fn reduced(self, factor: i32) -> Self {
+        Self {
+            x: self.x / factor,
+            y: self.y / factor,
+            width: self.width / factor,
+            height: self.height / factor,
+        }
+    }
+}
+
+/// Finds picture inside uniform borders, frames and caption bands.
+///
+/// Image is split into bands by rows of uniform color, the tallest band is the picture and captions are the rest.
+/// Then the same is done for columns of that band. Returns `None` when there is nothing to trim.
+pub fn find_content(image: &Mat) -> Option<Crop> {
+    let gray = image.cvt_color(ColorConversion::BGR2GRAY);
+    let rows = gray.rows as usize;
+    let cols = gray.cols as usize;
+    let pixels = gray.data();
+
+    let (top, bottom) = largest_band(rows, |row| {
+        is_uniform(pixels[row * cols..(row + 1) * cols].iter().cloned())
+    })?;
+    let (left, right) = largest_band(cols, |col| {
+        is_uniform((top..bottom).map(|row| pixels[row * cols + col]))
+    })?;
+
+    let (width, height) = (right - left, bottom - top);
+    if (width, height) == (cols, rows) || width < MIN_CONTENT_SIDE || height < MIN_CONTENT_SIDE {
+        return None;
+    }
+    if ((width * height) as f64) < MIN_CONTENT_AREA * (cols * rows) as f64 {
+        return None;
+    }
+    Some(Crop {
+        x: left as i32,
+        y: top as i32,
+        width: width as i32,
+        height: height as i32,
+    })
+}
+
+/// Finds panels of a collage separated by lines of uniform color.
+///
+/// Rows are split into bands first, then columns of every band. Returns nothing unless there are several panels.
+pub fn find_panels(image: &Mat) -> Vec<Crop> {
+    let gray = image.cvt_color(ColorConversion::BGR2GRAY);
+    let rows = gray.rows as usize;
+    let cols = gray.cols as usize;
+    let pixels = gray.data();
+    let min_height = (MIN_PANEL_SHARE * rows as f64) as usize;
+    let min_width = (MIN_PANEL_SHARE * cols as f64) as usize;
+
+    let mut panels = Vec::new();
+    let row_bands = bands(rows, |row| {
+        is_uniform(pixels[row * cols..(row + 1) * cols].iter().cloned())
+    });
+    for (top, bottom) in row_bands.into_iter().filter(|&(from, to)| to - from >= min_height) {
+        let column_bands = bands(cols, |col| {
+            is_uniform((top..bottom).map(|row| pixels[row * cols + col]))
+        });
+        for (left, right) in column_bands.into_iter().filter(|&(from, to)| to - from >= min_width) {
+            panels.push(Crop {
+                x: left as i32,
+                y: top as i32,
+                width: (right - left) as i32,
+                height: (bottom - top) as i32,
+            });
+        }
+    }
+    if panels.len() < 2 {
+        panels.clear();
+    }
+    panels
+}
+
+/// Returns the longest run of non uniform lines as a half-open range
+fn largest_band<F: Fn(usize) -> bool>(len: usize, is_uniform: F) -> Option<(usize, usize)> {
+    bands(len, is_uniform)
+        .into_iter()
+        .fold(None, |best: Option<(usize, usize)>, (from, to)| match best {
+            Some((best_from, best_to)) if best_to - best_from >= to - from => best,
+            _ => Some((from, to)),
+        })
+}
+
+/// Returns all runs of non uniform lines as half-open ranges
+fn bands<F: Fn(usize) -> bool>(len: usize, is_uniform: F) -> Vec<(usize, usize)> {
+    let mut bands = Vec::new();
+    let mut start = None;
+    for line in 0..=len {
+        let is_content = line < len && !is_uniform(line);
+        match (is_content, start) {
+            (true, None) => start = Some(line),
+            (false, Some(begin)) => {
+                bands.push((begin, line));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    bands
+}
+
+fn is_uniform<I: Iterator<Item = u8>>(values: I) -> bool {
+    let (mut count, mut sum, mut sum_squares) = (0.0, 0.0, 0.0);
+    for value in values {
+        let value = f64::from(value);
+        count += 1.0;
+        sum += value;
+        sum_squares += value * value;
+    }
+    if count == 0.0 {
+        return true;
+    }
+    let mean = sum / count;
+    let variance = sum_squares / count - mean * mean;
+    variance <= UNIFORM_STD_DEV * UNIFORM_STD_DEV
+}
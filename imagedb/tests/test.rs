@@ -1,8 +1,18 @@
 use imagedb;
 
+use cv::imgcodecs::*;
+use cv::imgproc::*;
+use cv::videoio::*;
+use cv::*;
 use imagedb::*;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[derive(Debug, Copy, Clone)]
 struct TestMetadata {
@@ -27,8 +37,116 @@ impl PartialEq for TestMetadata {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FileMetadata {
+    file_name: String,
+}
+
+impl FileMetadata {
+    pub fn new(file_name: &str) -> Self {
+        Self {
+            file_name: file_name.to_string(),
+        }
+    }
+}
+
+impl Metadata for FileMetadata {
+    fn file_name(&self) -> &str {
+        &self.file_name
+    }
+}
+
+/// Shares underlying storage between databases and counts how many images were read back
+#[derive(Clone)]
+struct CountingStorage {
+    inner: Rc<RefCell<InMemoryStorage<TestMetadata>>>,
+    loaded_images: Rc<Cell<usize>>,
+}
+
+impl CountingStorage {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(InMemoryStorage::new())),
+            loaded_images: Rc::new(Cell::new(0)),
+        }
+    }
+}
+
+impl Storage<TestMetadata> for CountingStorage {
+    fn save_image(&mut self, image: &Image<TestMetadata>) -> Result<(), ImageDbError> {
+        self.inner.borrow_mut().save_image(image)
+    }
+
+    fn load_images(&self) -> Result<Vec<Image<TestMetadata>>, ImageDbError> {
+        self.inner.borrow().load_images()
+    }
+
+    fn save_hash(&mut self, metadata: &TestMetadata, hash: &CachedHash) -> Result<(), ImageDbError> {
+        self.inner.borrow_mut().save_hash(metadata, hash)
+    }
+
+    fn save_digest(&mut self, metadata: &TestMetadata, digest: &[u8]) -> Result<(), ImageDbError> {
+        self.inner.borrow_mut().save_digest(metadata, digest)
+    }
+
+    fn load_entries(&self) -> Result<Vec<StoredEntry<TestMetadata>>, ImageDbError> {
+        self.inner.borrow().load_entries()
+    }
+
+    fn load_image(&self, metadata: &TestMetadata) -> Result<Option<Image<TestMetadata>>, ImageDbError> {
+        self.loaded_images.set(self.loaded_images.get() + 1);
+        self.inner.borrow().load_image(metadata)
+    }
+    fn remove_image(&mut self, metadata: &TestMetadata) -> Result<(), ImageDbError> {
+        self.inner.borrow_mut().remove_image(metadata)
+    }
+}
+
+/// Remembers sizes of images it hashed
+struct RecordingHasher {
+    inner: Box<dyn PerceptualHasher>,
+    sizes: Arc<Mutex<Vec<(i32, i32)>>>,
+}
+
+impl PerceptualHasher for RecordingHasher {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn version(&self) -> u32 {
+        self.inner.version()
+    }
+
+    fn compute(&self, image: &Mat) -> ImageHash {
+        self.sizes.lock().unwrap().push((image.cols, image.rows));
+        self.inner.compute(image)
+    }
+
+    fn compare(&self, lhs: &ImageHash, rhs: &ImageHash) -> f64 {
+        self.inner.compare(lhs, rhs)
+    }
+
+    fn default_threshold(&self) -> f64 {
+        self.inner.default_threshold()
+    }
+
+    fn is_binary(&self) -> bool {
+        self.inner.is_binary()
+    }
+
+    fn working_size(&self) -> Option<i32> {
+        self.inner.working_size()
+    }
+}
+
 #[test]
 fn detects_similar_images() {
+    for &threshold in &[1.0, 1.1, 1.2] {
+        detects_similar_images_with_threshold(threshold);
+    }
+}
+
+fn detects_similar_images_with_threshold(threshold: f64) {
     let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
     let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
     let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
@@ -37,14 +155,37 @@ fn detects_similar_images() {
     let solvay_conference = Image::new(solvay_conference, TestMetadata::new("3"));
 
     let storage = imagedb::InMemoryStorage::new();
-    let mut db = imagedb::ImageDb::new(storage);
-    let result = db.save_image_if_new(lenna.clone());
-    let result_demotivator = db.save_image_if_new(lenna_demotivator);
-    let result_solvay_conference = db.save_image_if_new(solvay_conference);
+    let mut db = imagedb::ImageDb::with_threshold(storage, ColorMomentHasher::new(), threshold).unwrap();
+    let result = db.save_image_if_new(lenna.clone()).unwrap();
+    let result_demotivator = db.save_image_if_new(lenna_demotivator).unwrap();
+    let result_solvay_conference = db.save_image_if_new(solvay_conference).unwrap();
 
-    assert_eq!(result, ImageVariant::New);
-    assert_eq!(result_demotivator, ImageVariant::AlreadyExists(lenna.metadata));
-    assert_eq!(result_solvay_conference, ImageVariant::New);
+    assert_eq!(result, ImageVariant::New, "threshold {}", threshold);
+    assert_eq!(
+        result_demotivator,
+        ImageVariant::AlreadyExists(lenna.metadata),
+        "threshold {}",
+        threshold
+    );
+    assert_eq!(result_solvay_conference, ImageVariant::New, "threshold {}", threshold);
+}
+
+#[test]
+fn threshold_could_be_changed_at_runtime() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    assert_eq!(1.0, db.threshold());
+    db.save_image_if_new(lenna.clone()).unwrap();
+
+    db.set_threshold(0.0);
+
+    assert_eq!(db.save_image_if_new(lenna_demotivator).unwrap(), ImageVariant::New);
+    assert_eq!(2, db.image_count());
 }
 
 #[test]
@@ -57,14 +198,14 @@ fn could_load_images() {
     let solvay_conference = Image::new(solvay_conference, TestMetadata::new("3"));
 
     let mut storage = imagedb::InMemoryStorage::new();
-    storage.save_image(&lenna);
-    storage.save_image(&solvay_conference);
+    storage.save_image(&lenna).unwrap();
+    storage.save_image(&solvay_conference).unwrap();
 
-    let mut db = imagedb::ImageDb::new(storage);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
 
-    let result = db.save_image_if_new(lenna.clone());
-    let result_demotivator = db.save_image_if_new(lenna_demotivator);
-    let result_solvay_conference = db.save_image_if_new(solvay_conference.clone());
+    let result = db.save_image_if_new(lenna.clone()).unwrap();
+    let result_demotivator = db.save_image_if_new(lenna_demotivator).unwrap();
+    let result_solvay_conference = db.save_image_if_new(solvay_conference.clone()).unwrap();
 
     assert_eq!(2, db.image_count());
     assert_eq!(result, ImageVariant::AlreadyExists(lenna.metadata.clone()));
@@ -75,6 +216,1174 @@ fn could_load_images() {
     );
 }
 
+#[test]
+fn every_hasher_detects_exact_copy() {
+    for name in &[
+        "average",
+        "difference",
+        "dct",
+        "block-mean",
+        "marr-hildreth",
+        "radial-variance",
+        "color-moment",
+    ] {
+        let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+        let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+        let lenna_copy = Image::new(reencode(&lenna), TestMetadata::new("3"));
+        let lenna = Image::new(lenna, TestMetadata::new("1"));
+        let solvay_conference = Image::new(solvay_conference, TestMetadata::new("2"));
+
+        let storage = imagedb::InMemoryStorage::new();
+        let mut db = imagedb::ImageDb::new(storage, hasher_by_name(name).unwrap()).unwrap();
+
+        assert_eq!(
+            db.save_image_if_new(lenna.clone()).unwrap(),
+            ImageVariant::New,
+            "{}",
+            name
+        );
+        assert_eq!(
+            db.save_image_if_new(lenna_copy).unwrap(),
+            ImageVariant::AlreadyExists(lenna.metadata),
+            "{}",
+            name
+        );
+        assert_eq!(
+            db.save_image_if_new(solvay_conference).unwrap(),
+            ImageVariant::New,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn ensemble_detects_exact_copy_with_every_rule() {
+    for &rule in &[
+        CombinationRule::AllAgree,
+        CombinationRule::Majority,
+        CombinationRule::WeightedSum,
+    ] {
+        let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+        let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+        let lenna_copy = Image::new(reencode(&lenna), TestMetadata::new("3"));
+        let lenna = Image::new(lenna, TestMetadata::new("1"));
+        let solvay_conference = Image::new(solvay_conference, TestMetadata::new("2"));
+
+        let hasher = EnsembleHasher::new(
+            rule,
+            vec![
+                (hasher_by_name("color-moment").unwrap(), 2.0),
+                (hasher_by_name("dct").unwrap(), 1.0),
+                (hasher_by_name("difference").unwrap(), 1.0),
+            ],
+        );
+        let storage = imagedb::InMemoryStorage::new();
+        let mut db = imagedb::ImageDb::new(storage, hasher).unwrap();
+
+        assert_eq!(
+            db.save_image_if_new(lenna.clone()).unwrap(),
+            ImageVariant::New,
+            "{:?}",
+            rule
+        );
+        assert_eq!(
+            db.save_image_if_new(lenna_copy).unwrap(),
+            ImageVariant::AlreadyExists(lenna.metadata),
+            "{:?}",
+            rule
+        );
+        assert_eq!(
+            db.save_image_if_new(solvay_conference).unwrap(),
+            ImageVariant::New,
+            "{:?}",
+            rule
+        );
+    }
+}
+
+#[test]
+fn finds_nearest_images_with_distances() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("3"));
+
+    let mut storage = imagedb::InMemoryStorage::new();
+    storage.save_image(&lenna_demotivator).unwrap();
+    storage.save_image(&solvay_conference).unwrap();
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+
+    let nearest = db.find_nearest(&lenna, 5);
+    assert_eq!(2, nearest.len());
+    assert_eq!(lenna_demotivator.metadata, nearest[0].metadata);
+    assert_eq!(solvay_conference.metadata, nearest[1].metadata);
+    assert!(nearest[0].distance < db.threshold());
+    assert!(nearest[0].distance <= nearest[1].distance);
+
+    let result = db.save_image_if_new_ranked(lenna, 5).unwrap();
+    let best = result.best().unwrap();
+    assert_eq!(lenna_demotivator.metadata, best.metadata);
+    assert_eq!(nearest[0].distance, best.distance);
+    assert_eq!(2, db.image_count());
+}
+
+#[test]
+fn find_similar_does_not_save_images() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+
+    assert!(db.find_similar(&lenna, 1).best().is_none());
+    assert_eq!(0, db.image_count());
+
+    db.insert(lenna.clone()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    assert_eq!(2, db.image_count());
+
+    let result = db.find_similar(&lenna_demotivator, 5);
+    assert_eq!(lenna.metadata, result.best().unwrap().metadata);
+    assert_eq!(2, db.image_count());
+}
+
+#[test]
+fn bk_tree_finds_same_images_as_linear_scan() {
+    let hashes = (0..500u64)
+        .map(|i| {
+            let bits = i.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (i << 17);
+            bits.to_le_bytes().to_vec()
+        })
+        .collect::<Vec<_>>();
+    let mut tree = BkTree::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        tree.insert(hash.clone(), i);
+    }
+    assert_eq!(hashes.len(), tree.len());
+
+    for query in hashes.iter().step_by(37) {
+        let query = ImageHash::Binary(query.clone());
+        for &radius in &[0, 8, 24, 64] {
+            let mut expected = hashes
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| (query.hamming_distance(&ImageHash::Binary(hash.clone())).unwrap(), i))
+                .filter(|&(distance, _)| distance <= radius)
+                .collect::<Vec<_>>();
+            expected.sort();
+            expected.truncate(10);
+
+            let bits = match query {
+                ImageHash::Binary(ref bits) => bits,
+                _ => unreachable!(),
+            };
+            let actual = tree.find_nearest(bits, 10, radius);
+            let expected_distances = expected.iter().map(|x| x.0).collect::<Vec<_>>();
+            let actual_distances = actual.iter().map(|x| x.0).collect::<Vec<_>>();
+            assert_eq!(expected_distances, actual_distances, "radius {}", radius);
+        }
+    }
+}
+
+#[test]
+fn bk_tree_skips_removed_and_follows_replaced_items() {
+    let hashes = (0..64u64)
+        .map(|i| (i * 0x0101).to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    let mut tree = BkTree::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        tree.insert(hash.clone(), i);
+    }
+    // Duplicate hash with another item
+    tree.insert(hashes[10].clone(), 64);
+
+    assert!(tree.remove(&hashes[10], 10));
+    assert!(!tree.remove(&hashes[10], 10));
+    assert_eq!(vec![(0, 64)], tree.find_nearest(&hashes[10], 1, 0));
+    assert!(tree.replace(&hashes[10], 64, 10));
+    assert_eq!(vec![(0, 10)], tree.find_nearest(&hashes[10], 2, 0));
+    assert_eq!(hashes.len(), tree.len());
+
+    for (i, hash) in hashes.iter().enumerate().skip(1) {
+        assert!(tree.remove(hash, i));
+    }
+    assert!(tree.is_sparse());
+    assert_eq!(vec![(0, 0)], tree.find_nearest(&hashes[0], 10, 64));
+}
+
+#[test]
+fn uses_cached_hashes_unless_stale() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("3"));
+
+    let storage = CountingStorage::new();
+    let mut db = imagedb::ImageDb::new(storage.clone(), ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference).unwrap();
+
+    let mut db = imagedb::ImageDb::new(storage.clone(), ColorMomentHasher::new()).unwrap();
+    assert_eq!(0, storage.loaded_images.get());
+    assert_eq!(
+        db.save_image_if_new(lenna_demotivator).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+
+    imagedb::ImageDb::new(storage.clone(), DctHasher::new()).unwrap();
+    assert_eq!(2, storage.loaded_images.get());
+
+    let mut db = imagedb::ImageDb::new(storage.clone(), DctHasher::new()).unwrap();
+    assert_eq!(2, storage.loaded_images.get());
+    let lenna_copy = Image::new(reencode(&lenna.bytes), TestMetadata::new("4"));
+    assert_eq!(
+        db.save_image_if_new(lenna_copy).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+}
+
+#[test]
+fn reports_progress_of_parallel_rehashing() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("2"));
+
+    let storage = CountingStorage::new();
+    let mut db = imagedb::ImageDb::new(storage.clone(), ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference).unwrap();
+
+    let mut reports = Vec::new();
+    let db = imagedb::ImageDb::with_progress(storage.clone(), DctHasher::new(), 1.0, Default::default(), |x| {
+        reports.push(x)
+    })
+    .unwrap();
+    assert_eq!(2, storage.loaded_images.get());
+    assert_eq!(2, db.image_count());
+    assert_eq!(Some(&LoadProgress { processed: 2, total: 2 }), reports.last());
+    assert_eq!(0.0, db.find_nearest(&lenna, 1)[0].distance);
+}
+
+#[test]
+fn hash_only_storage_detects_images_after_restart() {
+    let path = get_temp_dir("hash_only_storage");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let lenna_demotivator = Image::new(lenna_demotivator, FileMetadata::new("2.png"));
+    let solvay_conference = Image::new(solvay_conference, FileMetadata::new("3.jpg"));
+    let mode = StorageMode::HashOnly { keep_thumbnail: false };
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference).unwrap();
+    assert!(!path.join("1.png").exists());
+    assert!(!path.join("3.jpg").exists());
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    assert_eq!(2, db.image_count());
+    assert_eq!(
+        db.save_image_if_new(lenna_demotivator).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    assert_eq!(0, db.image_count());
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn thumbnails_allow_rehashing_with_another_algorithm() {
+    let path = get_temp_dir("thumbnail_storage");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let solvay_conference = Image::new(solvay_conference, FileMetadata::new("2.jpg"));
+    let mode = StorageMode::HashOnly { keep_thumbnail: true };
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference).unwrap();
+    assert!(!path.join("1.png").exists());
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    assert_eq!(2, db.image_count());
+    let lenna_copy = Image::new(reencode(&lenna.bytes), FileMetadata::new("3.bmp"));
+    assert_eq!(
+        db.save_image_if_new(lenna_copy).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn corrupt_metadata_is_skipped() {
+    let path = get_temp_dir("corrupt_metadata");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    fs::write(path.join("2.json"), b"{ not a json").unwrap();
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    assert_eq!(1, db.image_count());
+    assert_eq!(
+        db.save_image_if_new(lenna).unwrap(),
+        ImageVariant::AlreadyExists(FileMetadata::new("1.png"))
+    );
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn undecodable_images_are_reported_and_skipped() {
+    let path = get_temp_dir("undecodable_images");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let garbage = Image::new(b"definitely not an image".to_vec(), FileMetadata::new("2.png"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    assert_eq!(
+        db.save_image_if_new(garbage.clone()).unwrap(),
+        ImageVariant::Undecodable
+    );
+    assert!(db.find_nearest(&garbage, 1).is_empty());
+    assert!(db.insert(garbage).is_err());
+    assert_eq!(1, db.image_count());
+
+    fs::write(path.join("3.png"), b"broken file").unwrap();
+    fs::write(path.join("3.json"), br#"{"file_name":"3.png"}"#).unwrap();
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    assert_eq!(1, db.image_count());
+    assert_eq!(
+        db.save_image_if_new(lenna.clone()).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn removed_images_are_not_detected_anymore() {
+    let path = get_temp_dir("removed_images");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let solvay_conference = Image::new(solvay_conference, FileMetadata::new("2.jpg"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference.clone()).unwrap();
+
+    assert_eq!(Some(lenna.metadata.clone()), db.remove("1.png").unwrap());
+    assert_eq!(None, db.remove("1.png").unwrap());
+    assert_eq!(1, db.image_count());
+    assert!(!path.join("1.png").exists());
+    assert!(!path.join("1.json").exists());
+    assert_eq!(db.save_image_if_new(lenna.clone()).unwrap(), ImageVariant::New);
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    assert_eq!(2, db.image_count());
+    assert_eq!(
+        vec![solvay_conference.metadata.clone()],
+        db.remove_where(|x| x.file_name.ends_with(".jpg")).unwrap()
+    );
+    assert_eq!(
+        db.save_image_if_new(lenna.clone()).unwrap(),
+        ImageVariant::AlreadyExists(lenna.metadata)
+    );
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn expired_images_are_ignored_and_evicted() {
+    let path = get_temp_dir("expired_images");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let lenna_demotivator = Image::new(lenna_demotivator, FileMetadata::new("2.png"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.set_retention(Some(Duration::from_secs(3600)));
+    assert_eq!(
+        ImageVariant::AlreadyExists(lenna.metadata.clone()),
+        db.find_similar(&lenna_demotivator, 1).into()
+    );
+
+    db.set_retention(Some(Duration::from_millis(50)));
+    thread::sleep(Duration::from_millis(100));
+    assert!(db.find_similar(&lenna_demotivator, 1).best().is_none());
+    assert!(db.find_nearest(&lenna_demotivator, 1).is_empty());
+    assert_eq!(1, db.image_count());
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.set_retention(Some(Duration::from_millis(50)));
+    assert_eq!(vec![lenna.metadata], db.evict_expired().unwrap());
+    assert_eq!(0, db.image_count());
+    assert!(!path.join("1.json").exists());
+    assert_eq!(db.save_image_if_new(lenna_demotivator).unwrap(), ImageVariant::New);
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn copied_storage_keeps_age_of_images() {
+    let path = get_temp_dir("copied_storage");
+    let copy_path = get_temp_dir("copied_storage_copy");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    // Copies get new modification times
+    for entry in fs::read_dir(&path).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), copy_path.join(entry.file_name())).unwrap();
+    }
+
+    let storage = FileStorage::<FileMetadata>::new(copy_path.clone());
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.set_retention(Some(Duration::from_millis(50)));
+    assert_eq!(vec![lenna.metadata], db.evict_expired().unwrap());
+    assert!(!copy_path.join("1.time").exists());
+
+    fs::remove_dir_all(path).unwrap();
+    fs::remove_dir_all(copy_path).unwrap();
+}
+
+#[test]
+fn evicts_images_over_capacity() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("3"));
+
+    for &(policy, expected) in &[
+        (EvictionPolicy::OldestFirst, lenna.metadata),
+        (EvictionPolicy::LeastRecentlyMatched, solvay_conference.metadata),
+    ] {
+        let storage = CountingStorage::new();
+        let mut db = imagedb::ImageDb::new(storage.clone(), ColorMomentHasher::new()).unwrap();
+        db.set_capacity(Some(2));
+        db.set_eviction_policy(policy);
+        db.insert(lenna.clone()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        db.insert(solvay_conference.clone()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            db.save_image_if_new(lenna_demotivator.clone()).unwrap(),
+            ImageVariant::AlreadyExists(lenna.metadata)
+        );
+        thread::sleep(Duration::from_millis(10));
+        db.insert(lenna_demotivator.clone()).unwrap();
+
+        assert_eq!(2, db.image_count());
+        let stored = storage.load_entries().unwrap();
+        assert_eq!(2, stored.len());
+        assert!(stored.iter().all(|x| x.metadata != expected), "{:?}", policy);
+    }
+}
+
+#[test]
+fn byte_identical_copies_are_matched_by_digest() {
+    let path = get_temp_dir("digests");
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Image::new(lenna, FileMetadata::new("1.png"));
+    let mode = StorageMode::HashOnly { keep_thumbnail: false };
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    assert!(path.join("1.sha256").exists());
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, ColorMomentHasher::new()).unwrap();
+    let copy = Image::new(lenna.bytes.clone(), FileMetadata::new("2.png"));
+    let result = db.save_image_if_new_ranked(copy, 5).unwrap();
+    let best = result.best().unwrap();
+    assert_eq!(lenna.metadata, best.metadata);
+    assert_eq!(0.0, best.distance);
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn detects_rotated_and_mirrored_copies() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+
+    for &(transform, inverse) in &[
+        (Transform::FlipHorizontal, Transform::FlipHorizontal),
+        (Transform::Rotate90, Transform::Rotate270),
+    ] {
+        let bytes = transform.apply(&lenna_mat).image_encode(".png", Vec::new()).unwrap();
+        let copy = Image::new(bytes, TestMetadata::new("2"));
+
+        db.set_transform_invariant(false);
+        assert!(db.find_similar(&copy, 1).best().is_none(), "{:?}", transform);
+
+        db.set_transform_invariant(true);
+        let result = db.find_similar(&copy, 1);
+        let best = result.best().unwrap();
+        assert_eq!(lenna.metadata, best.metadata);
+        assert_eq!(inverse, best.transform);
+    }
+}
+
+#[test]
+fn trimmed_framed_copy_matches_original_at_strict_threshold() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_demotivator = fs::read(get_asset_path("lenna_demotivator.png")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let lenna_demotivator = Image::new(lenna_demotivator, TestMetadata::new("2"));
+
+    let storage = CountingStorage::new();
+    let mut db = imagedb::ImageDb::with_threshold(storage.clone(), DctHasher::new(), 6.0).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    let preprocessing = Preprocessing {
+        trim_borders: true,
+        ..Default::default()
+    };
+    let db = imagedb::ImageDb::with_preprocessing(storage.clone(), DctHasher::new(), 6.0, preprocessing).unwrap();
+    assert_eq!(1, storage.loaded_images.get());
+
+    let nearest = db.find_nearest(&lenna, 1);
+    assert_eq!(None, nearest[0].crop);
+
+    let result = db.find_similar(&lenna_demotivator, 1);
+    let best = result.best().unwrap();
+    assert_eq!(lenna.metadata, best.metadata);
+    let crop = best.crop.unwrap();
+    assert!(crop.x > 0 && crop.y > 0);
+
+    imagedb::ImageDb::new(storage.clone(), DctHasher::new()).unwrap();
+    assert_eq!(2, storage.loaded_images.get());
+}
+
+#[test]
+fn cropped_copy_is_confirmed_by_local_features() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let cropped = lenna_mat.roi(Rect::new(
+        lenna_mat.cols / 4,
+        lenna_mat.rows / 4,
+        lenna_mat.cols / 2,
+        lenna_mat.rows / 2,
+    ));
+    let cropped = cropped.clone().image_encode(".png", Vec::new()).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("2"));
+    let cropped = Image::new(cropped, TestMetadata::new("3"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_threshold(storage, DctHasher::new(), 1.0).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    db.insert(solvay_conference).unwrap();
+    assert!(db.find_similar(&cropped, 1).best().is_none());
+
+    let verification = FeatureVerification {
+        candidates: 2,
+        min_matches: 12,
+    };
+    db.set_verification(Some(verification));
+    let result = db.find_similar(&cropped, 1);
+    let best = result.best().unwrap();
+    assert_eq!(lenna.metadata, best.metadata);
+    assert!(best.feature_matches.unwrap() >= verification.min_matches);
+}
+
+#[test]
+fn crop_of_large_image_is_confirmed_at_another_scale() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let large = Mat::image_decode(&lenna, ImageReadMode::Color)
+        .resize_to(Size2i::new(1280, 1280), InterpolationFlag::InterCubic);
+    // Both images are downscaled to 512 pixels before looking for keypoints, so the crop is twice larger there
+    let cropped = large.roi(Rect::new(320, 320, 640, 640)).clone();
+    let large = large.image_encode(".png", Vec::new()).unwrap();
+    let cropped = cropped.image_encode(".png", Vec::new()).unwrap();
+    let large = Image::new(large, TestMetadata::new("1"));
+    let cropped = Image::new(cropped, TestMetadata::new("2"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_threshold(storage, DctHasher::new(), 1.0).unwrap();
+    db.insert(large.clone()).unwrap();
+    let verification = FeatureVerification {
+        candidates: 1,
+        min_matches: 12,
+    };
+    db.set_verification(Some(verification));
+    let result = db.find_similar(&cropped, 1);
+    let best = result.best().unwrap();
+    assert_eq!(large.metadata, best.metadata);
+    assert!(best.feature_matches.unwrap() >= verification.min_matches);
+}
+
+#[test]
+fn detects_known_images_inside_collages() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let solvay_mat = Mat::image_decode(&solvay_conference, ImageReadMode::Color).resize_to(
+        Size2i::new(lenna_mat.cols, lenna_mat.rows),
+        InterpolationFlag::InterArea,
+    );
+    let collage = side_by_side(&lenna_mat, &solvay_mat)
+        .image_encode(".png", Vec::new())
+        .unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let collage = Image::new(collage, TestMetadata::new("2"));
+    let preprocessing = Preprocessing {
+        tiles: true,
+        ..Default::default()
+    };
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_preprocessing(storage, DctHasher::new(), 6.0, preprocessing).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    let result = db.find_similar(&collage, 1);
+    let best = result.best().unwrap();
+    assert_eq!(lenna.metadata, best.metadata);
+    match best.tile {
+        Some(TileMatch::Query(region)) => assert_eq!(0, region.x),
+        tile => panic!("unexpected tile {:?}", tile),
+    }
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_preprocessing(storage, DctHasher::new(), 6.0, preprocessing).unwrap();
+    db.insert(collage.clone()).unwrap();
+    let result = db.find_similar(&lenna, 1);
+    let best = result.best().unwrap();
+    assert_eq!(collage.metadata, best.metadata);
+    match best.tile {
+        Some(TileMatch::Stored(region)) => assert_eq!(0, region.x),
+        tile => panic!("unexpected tile {:?}", tile),
+    }
+}
+
+#[test]
+fn classifies_matches_into_tiers() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let encode = |mat: &Mat, extension| mat.image_encode(extension, Vec::new()).unwrap();
+    let meme = Image::new(encode(&with_caption(&lenna_mat, 2), ".png"), TestMetadata::new("1"));
+    let identical = Image::new(meme.bytes.clone(), TestMetadata::new("2"));
+    let re_encoded = Image::new(encode(&with_caption(&lenna_mat, 2), ".jpg"), TestMetadata::new("3"));
+    let new_text = Image::new(encode(&with_caption(&lenna_mat, 5), ".png"), TestMetadata::new("4"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_threshold(storage, DctHasher::new(), 16.0).unwrap();
+    db.insert(meme.clone()).unwrap();
+
+    for &(ref image, tier) in &[
+        (identical, MatchTier::Identical),
+        (re_encoded, MatchTier::ReEncoded),
+        (new_text.clone(), MatchTier::SameTemplate),
+    ] {
+        let result = db.find_similar(image, 1);
+        let best = result.best().unwrap();
+        assert_eq!(meme.metadata, best.metadata);
+        assert_eq!(tier, best.tier);
+    }
+
+    let result = db.save_image_if_new_ranked(new_text, 1).unwrap();
+    assert_eq!(MatchTier::SameTemplate, result.best().unwrap().tier);
+    assert_eq!(2, db.image_count());
+}
+
+#[test]
+fn reports_same_template_evicted_by_image_with_new_text() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let encode = |mat: &Mat| mat.image_encode(".png", Vec::new()).unwrap();
+    let meme = Image::new(encode(&with_caption(&lenna_mat, 2)), TestMetadata::new("1"));
+    let solvay_conference = Image::new(solvay_conference, TestMetadata::new("2"));
+    let new_text = Image::new(encode(&with_caption(&lenna_mat, 5)), TestMetadata::new("3"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::with_threshold(storage, DctHasher::new(), 16.0).unwrap();
+    db.set_capacity(Some(2));
+    db.insert(meme.clone()).unwrap();
+    thread::sleep(Duration::from_millis(10));
+    db.insert(solvay_conference.clone()).unwrap();
+    thread::sleep(Duration::from_millis(10));
+
+    // The meme is the oldest one, so it is evicted when the image with new text is saved
+    let result = db.save_image_if_new_ranked(new_text.clone(), 2).unwrap();
+    let best = result.best().unwrap();
+    assert_eq!(meme.metadata, best.metadata);
+    assert_eq!(MatchTier::SameTemplate, best.tier);
+    assert_eq!(2, db.image_count());
+    assert_eq!(
+        ImageVariant::AlreadyExists(new_text.metadata),
+        db.find_similar(&new_text, 1).into()
+    );
+}
+
+#[test]
+fn detects_formats_by_magic_bytes() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    for &(extension, format) in &[
+        (".png", ImageFormat::Png),
+        (".jpg", ImageFormat::Jpeg),
+        (".bmp", ImageFormat::Bmp),
+        (".tiff", ImageFormat::Tiff),
+    ] {
+        let bytes = lenna_mat.image_encode(extension, Vec::new()).unwrap();
+        assert_eq!(Some(format), ImageFormat::detect(&bytes), "{}", extension);
+    }
+    assert_eq!(Some(ImageFormat::WebP), ImageFormat::detect(b"RIFF\x24\0\0\0WEBPVP8 "));
+    assert_eq!(None, ImageFormat::detect(b"not an image"));
+}
+
+#[test]
+fn webp_bmp_and_tiff_copies_match_original() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    for &(extension, format) in &[
+        (".webp", ImageFormat::WebP),
+        (".bmp", ImageFormat::Bmp),
+        (".tiff", ImageFormat::Tiff),
+    ] {
+        let bytes = lenna_mat.image_encode(extension, Vec::new()).unwrap();
+        assert_eq!(Some(format), ImageFormat::detect(&bytes), "{}", extension);
+        let copy = Image::new(bytes, TestMetadata::new("2"));
+        let result = db.find_similar(&copy, 1);
+        let best = result.best().unwrap();
+        assert_eq!(lenna.metadata, best.metadata, "{}", extension);
+        assert_eq!(MatchTier::ReEncoded, best.tier, "{}", extension);
+    }
+}
+
+#[test]
+fn reads_jpeg_dimensions_from_header() {
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let mat = Mat::image_decode(&solvay_conference, ImageReadMode::Color);
+    let expected = Dimensions {
+        width: mat.cols as u32,
+        height: mat.rows as u32,
+    };
+    assert_eq!(Some(expected), read_dimensions(&solvay_conference));
+    assert_eq!(None, read_dimensions(&solvay_conference[..100]));
+}
+
+#[test]
+fn reads_dimensions_of_every_still_format() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let expected = Dimensions {
+        width: 512,
+        height: 512,
+    };
+    for extension in &[".png", ".jpg", ".bmp", ".tiff"] {
+        let bytes = lenna_mat.image_encode(extension, Vec::new()).unwrap();
+        assert_eq!(Some(expected), read_dimensions(&bytes), "{}", extension);
+    }
+    assert_eq!(Some(expected), read_dimensions(&encode_gif(&[lenna_mat])));
+}
+
+#[test]
+fn rejects_decompression_bombs_by_header() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    // Only signature and IHDR chunk, decoder would allocate 30 GB for such image
+    let mut bomb = b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR".to_vec();
+    bomb.extend_from_slice(&100_000u32.to_be_bytes());
+    bomb.extend_from_slice(&100_000u32.to_be_bytes());
+    bomb.extend_from_slice(&[8, 2, 0, 0, 0, 0, 0, 0, 0]);
+    let bomb = Image::new(bomb, TestMetadata::new("2"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    let expected = Rejection::TooManyPixels {
+        pixels: 10_000_000_000,
+        limit: ImageLimits::default().max_pixels,
+    };
+    match db.find_similar(&bomb, 1) {
+        MatchResult::Rejected(rejection) => assert_eq!(expected, rejection),
+        other => panic!("Bomb was not rejected: {:?}", other),
+    }
+    assert_eq!(
+        ImageVariant::Rejected(expected),
+        db.save_image_if_new(bomb.clone()).unwrap()
+    );
+    assert!(db.find_nearest(&bomb, 1).is_empty());
+    assert!(db.insert(bomb).is_err());
+    assert_eq!(1, db.image_count());
+}
+
+#[test]
+fn rejects_images_over_byte_limit() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let size = lenna.len();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.set_limits(ImageLimits {
+        max_bytes: size - 1,
+        ..ImageLimits::default()
+    });
+    assert_eq!(
+        ImageVariant::Rejected(Rejection::TooManyBytes {
+            bytes: size,
+            limit: size - 1,
+        }),
+        db.save_image_if_new(lenna.clone()).unwrap()
+    );
+    assert_eq!(0, db.image_count());
+
+    db.set_limits(ImageLimits::default());
+    db.insert(lenna.clone()).unwrap();
+    assert_eq!(lenna.metadata, db.find_similar(&lenna, 1).best().unwrap().metadata);
+}
+
+#[test]
+fn limits_apply_to_images_rehashed_on_load() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let size = lenna.len();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let storage = CountingStorage::new();
+    let mut db = imagedb::ImageDb::new(storage.clone(), ColorMomentHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+
+    for &(max_bytes, expected) in &[(size - 1, 0), (size, 1)] {
+        let limits = ImageLimits {
+            max_bytes,
+            ..ImageLimits::default()
+        };
+        let hasher = DctHasher::new();
+        let threshold = hasher.default_threshold();
+        let db = imagedb::ImageDb::with_limits(
+            storage.clone(),
+            hasher,
+            threshold,
+            Preprocessing::default(),
+            limits,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(limits, db.limits());
+        assert_eq!(expected, db.image_count(), "{}", max_bytes);
+    }
+}
+
+#[test]
+fn large_jpeg_decoded_reduced_matches_original() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let large = Mat::image_decode(&lenna, ImageReadMode::Color)
+        .resize_to(Size2i::new(2048, 2048), InterpolationFlag::InterCubic)
+        .image_encode(".jpg", Vec::new())
+        .unwrap();
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let large = Image::new(large, TestMetadata::new("2"));
+
+    for hasher in &["dct", "block-mean", "marr-hildreth"] {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let recording = RecordingHasher {
+            inner: hasher_by_name(hasher).unwrap(),
+            sizes: sizes.clone(),
+        };
+        let storage = imagedb::InMemoryStorage::new();
+        let mut db = imagedb::ImageDb::new(storage, recording).unwrap();
+        db.insert(lenna.clone()).unwrap();
+        sizes.lock().unwrap().clear();
+        let result = db.find_similar(&large, 1);
+        assert_eq!(lenna.metadata, result.best().unwrap().metadata, "{}", hasher);
+        let sizes = sizes.lock().unwrap();
+        assert!(!sizes.is_empty(), "{}", hasher);
+        assert!(
+            sizes.iter().all(|&(width, height)| width < 2048 && height < 2048),
+            "{}",
+            hasher
+        );
+    }
+}
+
+#[test]
+fn gif_copy_matches_original() {
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna_mat = Mat::image_decode(&lenna, ImageReadMode::Color);
+    let gif = encode_gif(&[lenna_mat]);
+    assert_eq!(Some(ImageFormat::Gif), ImageFormat::detect(&gif));
+    let lenna = Image::new(lenna, TestMetadata::new("1"));
+    let gif = Image::new(gif, TestMetadata::new("2"));
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(lenna.clone()).unwrap();
+    let result = db.find_similar(&gif, 1);
+    assert_eq!(lenna.metadata, result.best().unwrap().metadata);
+}
+
+#[test]
+fn trimmed_animation_matches_original_by_keyframes() {
+    let frames = distinct_frames();
+    assert_eq!(
+        4,
+        hash_keyframes(&DctHasher::new(), &encode_gif(&frames), &ImageLimits::default()).len()
+    );
+    let original = Image::new(encode_gif(&frames), TestMetadata::new("1"));
+    let trimmed = Image::new(encode_gif(&frames[1..]), TestMetadata::new("2"));
+    let reordered = Image::new(
+        encode_gif(&[frames[3].clone(), frames[2].clone()]),
+        TestMetadata::new("3"),
+    );
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(original.clone()).unwrap();
+    let result = db.find_similar(&trimmed, 1);
+    assert_eq!(original.metadata, result.best().unwrap().metadata);
+    assert!(db.find_similar(&reordered, 1).best().is_none());
+}
+
+#[test]
+fn trimmed_video_matches_original_by_keyframes() {
+    let frames = distinct_frames();
+    let original = Image::new(encode_video(&frames, "original_video"), TestMetadata::new("1"));
+    let trimmed = Image::new(encode_video(&frames[1..], "trimmed_video"), TestMetadata::new("2"));
+    assert_eq!(Some(ImageFormat::Mp4), ImageFormat::detect(&original.bytes));
+    assert_eq!(
+        4,
+        hash_keyframes(&DctHasher::new(), &original.bytes, &ImageLimits::default()).len()
+    );
+
+    let storage = imagedb::InMemoryStorage::new();
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(original.clone()).unwrap();
+    let result = db.find_similar(&trimmed, 1);
+    assert_eq!(original.metadata, result.best().unwrap().metadata);
+}
+
+#[test]
+fn animation_decoding_stops_at_pixel_limit() {
+    let frames = distinct_frames();
+    let gif = encode_gif(&frames);
+    let frame_pixels = (frames[0].rows * frames[0].cols) as u64;
+    let limits = |frame_count| ImageLimits {
+        max_decoded_pixels: frame_count * frame_pixels,
+        ..ImageLimits::default()
+    };
+    assert_eq!(2, hash_keyframes(&DctHasher::new(), &gif, &limits(2)).len());
+    // Single keyframe is not a sequence
+    assert!(hash_keyframes(&DctHasher::new(), &gif, &limits(1)).is_empty());
+}
+
+#[test]
+fn video_frame_size_is_checked() {
+    let frames = distinct_frames();
+    let video = encode_video(&frames, "large_video");
+    let frame_pixels = (frames[0].rows * frames[0].cols) as u64;
+    let limits = ImageLimits {
+        max_pixels: frame_pixels - 1,
+        ..ImageLimits::default()
+    };
+    assert_eq!(
+        Err(Rejection::TooManyPixels {
+            pixels: frame_pixels,
+            limit: frame_pixels - 1,
+        }),
+        limits.check(&video)
+    );
+    assert_eq!(Ok(()), ImageLimits::default().check(&video));
+}
+
+#[test]
+fn animation_cached_without_keyframes_is_rehashed() {
+    let path = get_temp_dir("animation_cache");
+    let frames = distinct_frames();
+    let original = Image::new(encode_gif(&frames), FileMetadata::new("1.gif"));
+    let trimmed = Image::new(encode_gif(&frames[1..]), FileMetadata::new("2.gif"));
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(original.clone()).unwrap();
+    // Caches written before keyframes were hashed have neither them nor revision
+    let hash_path = path.join("1.hash");
+    let mut cached: CachedHash = serde_json::from_slice(&fs::read(&hash_path).unwrap()).unwrap();
+    cached.frames.clear();
+    cached.revision = 0;
+    fs::write(&hash_path, serde_json::to_vec(&cached).unwrap()).unwrap();
+
+    let storage = FileStorage::<FileMetadata>::new(path.clone());
+    let db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    let result = db.find_similar(&trimmed, 1);
+    assert_eq!(original.metadata, result.best().unwrap().metadata);
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn animation_rehashed_from_thumbnail_keeps_keyframes() {
+    let path = get_temp_dir("animation_thumbnail");
+    let frames = distinct_frames();
+    let original = Image::new(encode_gif(&frames), FileMetadata::new("1.gif"));
+    let trimmed = Image::new(encode_gif(&frames[1..]), FileMetadata::new("2.gif"));
+    let mode = StorageMode::HashOnly { keep_thumbnail: true };
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let mut db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    db.insert(original.clone()).unwrap();
+    // Outdated cache is rehashed from the thumbnail, which is the first frame only
+    let hash_path = path.join("1.hash");
+    let mut cached: CachedHash = serde_json::from_slice(&fs::read(&hash_path).unwrap()).unwrap();
+    cached.revision = 0;
+    fs::write(&hash_path, serde_json::to_vec(&cached).unwrap()).unwrap();
+
+    let storage = FileStorage::<FileMetadata>::with_mode(path.clone(), mode);
+    let db = imagedb::ImageDb::new(storage, DctHasher::new()).unwrap();
+    let result = db.find_similar(&trimmed, 1);
+    assert_eq!(original.metadata, result.best().unwrap().metadata);
+    let rehashed: CachedHash = serde_json::from_slice(&fs::read(&hash_path).unwrap()).unwrap();
+    assert!(!rehashed.is_outdated());
+    assert_eq!(cached.frames, rehashed.frames);
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+/// The same pixels in another format, so the copy is matched by hash rather than by digest
+fn reencode(bytes: &[u8]) -> Vec<u8> {
+    Mat::image_decode(bytes, ImageReadMode::Color)
+        .image_encode(".bmp", Vec::new())
+        .unwrap()
+}
+
+/// Four frames that differ from each other a lot, every one is a keyframe when shown for half a second
+fn distinct_frames() -> [Mat; 4] {
+    let size = Size2i::new(128, 128);
+    let lenna = fs::read(get_asset_path("lenna.png")).unwrap();
+    let lenna = Mat::image_decode(&lenna, ImageReadMode::Color).resize_to(size, InterpolationFlag::InterArea);
+    let solvay_conference = fs::read(get_asset_path("Solvay_conference_1927.jpg")).unwrap();
+    let solvay_conference =
+        Mat::image_decode(&solvay_conference, ImageReadMode::Color).resize_to(size, InterpolationFlag::InterArea);
+    [
+        lenna.clone(),
+        solvay_conference.clone(),
+        Transform::FlipVertical.apply(&lenna),
+        Transform::Rotate180.apply(&solvay_conference),
+    ]
+}
+
+/// Writes MP4 video with every frame shown for half a second
+fn encode_video(frames: &[Mat], name: &str) -> Vec<u8> {
+    let dir = get_temp_dir(name);
+    let path = dir.join("video.mp4");
+    {
+        // Video is finished when writer is dropped
+        let writer = VideoWriter::new(
+            &path.to_string_lossy(),
+            i32::from_le_bytes(*b"mp4v"),
+            2.0,
+            frames[0].size(),
+            true,
+        );
+        assert!(writer.is_open());
+        for frame in frames {
+            writer.write(frame);
+        }
+    }
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+    bytes
+}
+
+fn encode_gif(frames: &[Mat]) -> Vec<u8> {
+    let (width, height) = (frames[0].cols as u16, frames[0].rows as u16);
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut bytes, width, height, &[]).unwrap();
+        for frame in frames {
+            let rgb: Vec<u8> = frame
+                .data()
+                .chunks(3)
+                .flat_map(|bgr| vec![bgr[2], bgr[1], bgr[0]])
+                .collect();
+            let mut frame = gif::Frame::from_rgb(width, height, &rgb);
+            frame.delay = 50;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    bytes
+}
+
+/// Copy of the image with a white band of black glyphs at the bottom, glyphs depend on `seed`
+fn with_caption(image: &Mat, seed: usize) -> Mat {
+    let (rows, cols) = (image.rows as usize, image.cols as usize);
+    let mut pixels = image.data().to_vec();
+    for row in rows * 3 / 4..rows * 7 / 8 {
+        for col in 0..cols {
+            let glyph = col / 16 * seed + (row - rows * 3 / 4) / 8;
+            let value = if col % 16 < 12 && glyph % 3 == 1 { 0 } else { 255 };
+            let offset = (row * cols + col) * 3;
+            pixels[offset..offset + 3].copy_from_slice(&[value; 3]);
+        }
+    }
+    Mat::from_buffer(image.rows, image.cols, CvType::Cv8UC3, &pixels).clone()
+}
+
+fn side_by_side(left: &Mat, right: &Mat) -> Mat {
+    let (left_row, right_row) = (left.cols as usize * 3, right.cols as usize * 3);
+    let mut pixels = Vec::with_capacity(left.data().len() + right.data().len());
+    for row in 0..left.rows as usize {
+        pixels.extend_from_slice(&left.data()[row * left_row..(row + 1) * left_row]);
+        pixels.extend_from_slice(&right.data()[row * right_row..(row + 1) * right_row]);
+    }
+    Mat::from_buffer(left.rows, left.cols + right.cols, CvType::Cv8UC3, &pixels).clone()
+}
+
+fn get_temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("imagedb_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
 pub fn get_asset_path(name: &'static str) -> PathBuf {
     Path::new(env!("CARGO_MANIFEST_DIR"))
         .join("tests")
@@ -27,6 +27,46 @@ pub struct PhotoSize {
     pub height: i64,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Animation {
+    pub file_id: String,
+    pub width: i64,
+    pub height: i64,
+    pub duration: i64,
+    pub thumb: Option<PhotoSize>,
+    pub file_name: Option<String>,
+    pub mime_type: Option<String>,
+    pub file_size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Video {
+    pub file_id: String,
+    pub width: i64,
+    pub height: i64,
+    pub duration: i64,
+    pub thumb: Option<PhotoSize>,
+    pub mime_type: Option<String>,
+    pub file_size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sticker {
+    pub file_id: String,
+    pub width: i64,
+    pub height: i64,
+    /// Animated stickers are vector animations, not images
+    #[serde(default)]
+    pub is_animated: bool,
+    /// Video stickers are WebM clips
+    #[serde(default)]
+    pub is_video: bool,
+    pub thumb: Option<PhotoSize>,
+    pub emoji: Option<String>,
+    pub set_name: Option<String>,
+    pub file_size: Option<i64>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct User {
     pub id: i64,
@@ -44,8 +84,19 @@ pub struct Message {
     pub chat: Chat,
     pub date: i64,
     pub text: Option<String>,
+    pub caption: Option<String>,
     pub photo: Option<Vec<PhotoSize>>,
     pub document: Option<Document>,
+    pub animation: Option<Animation>,
+    pub video: Option<Video>,
+    pub sticker: Option<Sticker>,
+    pub reply_to_message: Option<Box<Message>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatMember {
+    pub user: User,
+    pub status: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -4,9 +4,10 @@
 mod contract;
 mod telegram_client;
 
-use crate::contract::Update;
+use crate::contract::{Chat, Document, File, Update};
 use crate::telegram_client::*;
 use clap::{App, Arg};
+use futures::future::{self, Either};
 use futures::Stream;
 use hyper;
 use hyper::rt::{self, Future};
@@ -20,12 +21,22 @@ use serde_json::from_slice;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::await;
 use tokio::runtime::Runtime;
 use tokio_async_await::compat::backward;
 
 const STORAGE_DIR_NAME: &str = "storage";
+const SETTINGS_EXTENSION: &str = "json";
+const MAX_REPORTED_COPIES: usize = 5;
+const VERIFIED_CANDIDATES: usize = 3;
+const MIN_FEATURE_MATCHES: usize = 12;
+// Seconds, longer videos are not memes and take too long to decode
+const MAX_VIDEO_DURATION: i64 = 60;
+const STICKERS_DIR_NAME: &str = "stickers";
+// Stickers are only compared with recent ones, posting a popular sticker once in a while is fine
+const STICKER_RETENTION: Duration = Duration::from_secs(10 * 60);
 
 macro_rules! try_get_result {
     ($expr:expr, $error_message:literal) => (match $expr {
@@ -60,11 +71,71 @@ impl Metadata for ImageMetadata {
     }
 }
 
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct ChatSettings {
+    threshold: Option<f64>,
+    /// Whether repeated stickers are reported
+    stickers: bool,
+    sticker_threshold: Option<f64>,
+}
+
+impl ChatSettings {
+    pub fn load(chat_id: i64) -> Self {
+        std::fs::read(get_settings_path(chat_id))
+            .ok()
+            .and_then(|bytes| from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, chat_id: i64) -> std::io::Result<()> {
+        let json = serde_json::to_vec(self)?;
+        std::fs::write(get_settings_path(chat_id), json)
+    }
+}
+
+struct DbConfig {
+    algorithms: Vec<String>,
+    rule: CombinationRule,
+    storage_mode: StorageMode,
+    retention: Option<Duration>,
+    capacity: Option<usize>,
+    eviction_policy: EvictionPolicy,
+    transform_invariant: bool,
+    verification: Option<FeatureVerification>,
+    preprocessing: Preprocessing,
+    limits: ImageLimits,
+}
+
+impl DbConfig {
+    pub fn create_hasher(&self) -> Box<dyn PerceptualHasher> {
+        let mut hashers = self
+            .algorithms
+            .iter()
+            .map(|name| hasher_by_name(name).unwrap())
+            .collect::<Vec<_>>();
+        if hashers.len() == 1 {
+            return hashers.remove(0);
+        }
+        let members = hashers.into_iter().map(|hasher| (hasher, 1.0)).collect();
+        Box::new(EnsembleHasher::new(self.rule, members))
+    }
+}
+
+/// Every chat has separate databases of images and stickers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DbKind {
+    Images,
+    Stickers,
+}
+
 type Synced<T> = Arc<Mutex<T>>;
 type Storage = FileStorage<ImageMetadata>;
-type Db = ImageDb<ImageMetadata, Storage>;
-type SyncedDb = Synced<Db>;
-type DbTable = HashMap<i64, SyncedDb>;
+type Db = ImageDb<ImageMetadata, Storage, Box<dyn PerceptualHasher>>;
+type SyncedDb = Arc<RwLock<Db>>;
+/// Database is loaded on first use, the slot is locked while it loads so other chats are not blocked
+type DbSlot = Synced<Option<SyncedDb>>;
+type DbTable = HashMap<(i64, DbKind), DbSlot>;
 type SyncedDbMap = Synced<DbTable>;
 
 fn main() {
@@ -95,15 +166,158 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("hash")
+                .long("hash")
+                .help("Sets the perceptual hash algorithms used to compare images, comma separated")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .possible_values(&[
+                    "average",
+                    "difference",
+                    "dct",
+                    "block-mean",
+                    "marr-hildreth",
+                    "radial-variance",
+                    "color-moment",
+                ])
+                .default_value("color-moment"),
+        )
+        .arg(
+            Arg::with_name("combination")
+                .long("combination")
+                .help("Sets how decisions of several hash algorithms are combined")
+                .takes_value(true)
+                .possible_values(&["all", "majority", "weighted"])
+                .default_value("majority"),
+        )
+        .arg(
+            Arg::with_name("storageMode")
+                .long("storageMode")
+                .help("Sets whether original images, only their hashes or hashes with thumbnails are stored")
+                .takes_value(true)
+                .possible_values(&["full", "hash-only", "thumbnail"])
+                .default_value("full"),
+        )
+        .arg(
+            Arg::with_name("retentionDays")
+                .long("retentionDays")
+                .help("Sets how many days images are remembered, forever if not set")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("maxImages")
+                .long("maxImages")
+                .help("Sets maximum number of images remembered per chat, unlimited if not set")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eviction")
+                .long("eviction")
+                .help("Sets which images are forgotten first when chat has too many of them")
+                .takes_value(true)
+                .possible_values(&["oldest", "least-recently-matched"])
+                .default_value("oldest"),
+        )
+        .arg(
+            Arg::with_name("matchTransforms")
+                .long("matchTransforms")
+                .help("Enables detection of rotated and mirrored copies"),
+        )
+        .arg(
+            Arg::with_name("trimBorders")
+                .long("trimBorders")
+                .help("Enables trimming of frames and caption bands before comparing images"),
+        )
+        .arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .help("Enables detection of known images inside collages and collages of known images"),
+        )
+        .arg(
+            Arg::with_name("verifyFeatures")
+                .long("verifyFeatures")
+                .help("Enables detection of cropped copies by comparing local features of closest images"),
+        )
+        .arg(
+            Arg::with_name("maxPixels")
+                .long("maxPixels")
+                .help("Sets maximum number of pixels of an image, larger ones are not decoded")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("maxDecodedPixels")
+                .long("maxDecodedPixels")
+                .help("Sets maximum number of pixels in all decoded frames of an animation or video together")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("maxBytes")
+                .long("maxBytes")
+                .help("Sets maximum size of an image file in bytes, larger ones are not downloaded")
+                .takes_value(true),
+        )
         .get_matches();
 
     let bot_token = matches.value_of("token").unwrap();
     let address = matches.value_of("address").unwrap();
     let external_address = matches.value_of("externalAddress").unwrap();
-    run(bot_token, address, external_address);
+    let db_config = DbConfig {
+        algorithms: matches.values_of("hash").unwrap().map(|x| x.to_string()).collect(),
+        rule: match matches.value_of("combination").unwrap() {
+            "all" => CombinationRule::AllAgree,
+            "weighted" => CombinationRule::WeightedSum,
+            _ => CombinationRule::Majority,
+        },
+        storage_mode: match matches.value_of("storageMode").unwrap() {
+            "hash-only" => StorageMode::HashOnly { keep_thumbnail: false },
+            "thumbnail" => StorageMode::HashOnly { keep_thumbnail: true },
+            _ => StorageMode::Full,
+        },
+        retention: matches.value_of("retentionDays").map(|x| {
+            let days: u64 = x.parse().expect("retention should be a number of days");
+            Duration::from_secs(days * 24 * 60 * 60)
+        }),
+        capacity: matches
+            .value_of("maxImages")
+            .map(|x| x.parse().expect("maximum number of images should be a number")),
+        eviction_policy: match matches.value_of("eviction").unwrap() {
+            "least-recently-matched" => EvictionPolicy::LeastRecentlyMatched,
+            _ => EvictionPolicy::OldestFirst,
+        },
+        transform_invariant: matches.is_present("matchTransforms"),
+        verification: if matches.is_present("verifyFeatures") {
+            Some(FeatureVerification {
+                candidates: VERIFIED_CANDIDATES,
+                min_matches: MIN_FEATURE_MATCHES,
+            })
+        } else {
+            None
+        },
+        preprocessing: Preprocessing {
+            trim_borders: matches.is_present("trimBorders"),
+            tiles: matches.is_present("tiles"),
+        },
+        limits: {
+            let default = ImageLimits::default();
+            ImageLimits {
+                max_pixels: matches.value_of("maxPixels").map_or(default.max_pixels, |x| {
+                    x.parse().expect("maximum number of pixels should be a number")
+                }),
+                max_decoded_pixels: matches.value_of("maxDecodedPixels").map_or(default.max_decoded_pixels, |x| {
+                    x.parse().expect("maximum number of decoded pixels should be a number")
+                }),
+                max_bytes: matches.value_of("maxBytes").map_or(default.max_bytes, |x| {
+                    x.parse().expect("maximum file size should be a number of bytes")
+                }),
+            }
+        },
+    };
+    run(bot_token, address, external_address, db_config);
 }
 
-fn run(bot_token: &str, listening_address: &str, external_address: &str) {
+fn run(bot_token: &str, listening_address: &str, external_address: &str, db_config: DbConfig) {
     let listening_address: SocketAddr = listening_address
         .replace("localhost", "127.0.0.1")
         .parse()
@@ -127,13 +341,22 @@ fn run(bot_token: &str, listening_address: &str, external_address: &str) {
 
     let telegram_client = Arc::new(telegram_client);
     let dbs = Arc::new(Mutex::new(HashMap::new()));
+    let db_config = Arc::new(db_config);
 
     let server = Server::bind(&listening_address)
         .serve(move || {
             let telegram_client = telegram_client.clone();
             let dbs = dbs.clone();
-
-            service_fn(move |x| backward::Compat::new(handle_request(x, telegram_client.clone(), dbs.clone())))
+            let db_config = db_config.clone();
+
+            service_fn(move |x| {
+                backward::Compat::new(handle_request(
+                    x,
+                    telegram_client.clone(),
+                    dbs.clone(),
+                    db_config.clone(),
+                ))
+            })
         })
         .map_err(|e| error!("server error: {}", e));
 
@@ -145,9 +368,10 @@ async fn handle_request(
     req: Request<Body>,
     telegram_client: Arc<TelegramClient>,
     dbs: SyncedDbMap,
+    db_config: Arc<DbConfig>,
 ) -> Result<Response<Body>, hyper::Error> {
     info!("Got new request!");
-    let result = await!(handle_request_internal(req, telegram_client, dbs));
+    let result = await!(handle_request_internal(req, telegram_client, dbs, db_config));
     let response = match result {
         Ok(()) => Response::new(Body::empty()),
         Err(status_code) => Response::builder().status(status_code).body(Body::empty()).unwrap(),
@@ -159,21 +383,140 @@ async fn handle_request_internal(
     req: Request<Body>,
     telegram_client: Arc<TelegramClient>,
     dbs: SyncedDbMap,
+    db_config: Arc<DbConfig>,
 ) -> Result<(), StatusCode> {
     let chunk = await!(req.into_body().concat2()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let update: Update = from_slice(chunk.as_ref()).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
     let chat_id = update.message.chat.id;
     let message_id = update.message.message_id;
-    let processing_info = match (&update.message.from, &update.message.document, &update.message.photo) {
-        (Some(ref from), Some(ref document), _) => Some((from, &document.file_id)),
-        (Some(ref from), _, Some(ref photo)) => photo
-            .iter()
-            .max_by_key(|x| x.file_size.unwrap_or(0))
-            .map(|x| (from, &x.file_id)),
+
+    // Everyone may read settings, so administrators are only looked up when settings are changed
+    let is_admin = match (&update.message.from, &update.message.text) {
+        (Some(ref from), Some(ref text)) if changes_settings(text) => {
+            await!(is_chat_admin(&telegram_client, &update.message.chat, from.id)).unwrap_or(false)
+        }
+        _ => false,
+    };
+    let command_reply = update
+        .message
+        .text
+        .as_ref()
+        .and_then(|text| handle_command(text, chat_id, is_admin, &dbs, &db_config));
+    if let Some(reply) = command_reply {
+        await!(telegram_client.send_message(chat_id, &reply, Some(message_id))).map_err(|e| {
+            error!("Unknown exception while sending request: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(());
+    }
+
+    let is_forget = update.message.text.as_ref().and_then(|x| get_command(x)) == Some("/forget");
+    if is_forget {
+        let reply = match (&update.message.from, &update.message.reply_to_message) {
+            (Some(ref from), Some(ref original)) => {
+                let is_admin = await!(is_chat_admin(&telegram_client, &update.message.chat, from.id)).unwrap_or(false);
+                if is_admin {
+                    let db = get_or_create_db(&dbs, chat_id, DbKind::Images, &db_config)
+                        .map_err(|e| log_db_error(chat_id, e))?;
+                    let mut db = db.write().unwrap();
+                    let removed = db
+                        .remove_where(|x| x.message_id == original.message_id)
+                        .map_err(|e| log_db_error(chat_id, e))?;
+                    if removed.is_empty() {
+                        "Этой картинки нет в базе."
+                    } else {
+                        info!("Removed {:?} from chat {}", removed, chat_id);
+                        "Картинка удалена из базы."
+                    }
+                } else {
+                    "Удалять картинки из базы могут только администраторы чата."
+                }
+            }
+            _ => "Ответьте командой /forget на сообщение с картинкой, которую нужно удалить из базы.",
+        };
+        await!(telegram_client.send_message(chat_id, reply, Some(message_id))).map_err(|e| {
+            error!("Unknown exception while sending request: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(());
+    }
+
+    if let (Some(ref from), Some(ref sticker)) = (&update.message.from, &update.message.sticker) {
+        if sticker.is_animated || sticker.is_video || !ChatSettings::load(chat_id).stickers {
+            return Ok(());
+        }
+        let file = await!(telegram_client.get_file(&sticker.file_id)).map_err(|_| StatusCode::GATEWAY_TIMEOUT)?;
+        if is_too_large(&file, &db_config.limits) {
+            warn!("Sticker {} is too large. Skipping", sticker.file_id);
+            return Ok(());
+        }
+        let file_path = try_get_result!(file.file_path, "Sticker has no path. Skipping");
+        let bytes = await!(telegram_client.download_file(&file_path)).map_err(|_| StatusCode::GATEWAY_TIMEOUT)?;
+        let image = Image::new(
+            bytes.into_iter().collect(),
+            ImageMetadata::new(format!("{}.webp", sticker.file_id), from.id, message_id),
+        );
+        let result = {
+            let db = get_or_create_db(&dbs, chat_id, DbKind::Stickers, &db_config)
+                .map_err(|e| log_db_error(chat_id, e))?;
+            let mut db = db.write().unwrap();
+            db.save_image_if_new(image).map_err(|e| log_db_error(chat_id, e))?
+        };
+        if let ImageVariant::AlreadyExists(_) = result {
+            let text = format!(
+                "[{}](tg://user?id={}), этот стикер только что уже отправляли.",
+                &from.first_name, &from.id
+            );
+            await!(telegram_client.send_message(chat_id, &text, Some(message_id))).map_err(|e| {
+                error!("Unknown exception while sending request: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        }
+        return Ok(());
+    }
+
+    let is_check = update
+        .message
+        .text
+        .as_ref()
+        .or_else(|| update.message.caption.as_ref())
+        .and_then(|x| get_command(x))
+        == Some("/check");
+    // `/check` may be either a caption of an image or a reply to a message with one
+    let source = match (&update.message.text, &update.message.reply_to_message) {
+        (Some(_), Some(reply)) if is_check => &**reply,
+        _ => &update.message,
+    };
+
+    // Animations come with a document too, so they go first
+    let processing_info = match (&update.message.from, &source.animation, &source.video) {
+        (Some(ref from), Some(ref animation), _) => Some((from, &animation.file_id)),
+        (Some(ref from), _, Some(ref video)) if video.duration <= MAX_VIDEO_DURATION => Some((from, &video.file_id)),
+        (Some(ref from), _, _) => match (&source.document, &source.photo) {
+            (Some(ref document), _) if may_be_media(document) => Some((from, &document.file_id)),
+            (Some(_), _) => None,
+            (_, Some(ref photo)) => photo
+                .iter()
+                .max_by_key(|x| x.file_size.unwrap_or(0))
+                .map(|x| (from, &x.file_id)),
+            _ => None,
+        },
         _ => None,
     };
 
-    let (user, file_id) = try_get_result!(processing_info, "There is no sender or images. Skipping");
+    let (user, file_id) = try_get_result!(processing_info, "There is no sender or media. Skipping");
+    // Database checks frame size of videos itself, frame size reported by Telegram only saves downloading them
+    let frame_size = match (&source.animation, &source.video) {
+        (Some(ref animation), _) => Some((animation.width, animation.height)),
+        (_, Some(ref video)) => Some((video.width, video.height)),
+        _ => None,
+    };
+    if let Some((width, height)) = frame_size {
+        if (width.max(0) as u64) * (height.max(0) as u64) > db_config.limits.max_pixels {
+            warn!("Frames of {} are {}x{}, too large. Skipping", file_id, width, height);
+            return Ok(());
+        }
+    }
 
     let file = await!(telegram_client.get_file(file_id)).map_err(|_| StatusCode::GATEWAY_TIMEOUT)?;
     info!(
@@ -181,41 +524,90 @@ async fn handle_request_internal(
         file, user, chat_id, message_id
     );
 
-    let (file_path, ext) = try_get_result!(
-        get_file_path_if_processable(file.file_path),
-        "Unsupported extension. Skipping"
-    );
-    let bytes = await!(telegram_client.download_file(&file_path)).map_err(|_| StatusCode::GATEWAY_TIMEOUT)?;
+    if is_too_large(&file, &db_config.limits) {
+        warn!("File {} is too large. Skipping", file_id);
+        return Ok(());
+    }
+    let file_path = try_get_result!(file.file_path, "File has no path. Skipping");
+    let bytes: Vec<u8> = await!(telegram_client.download_file(&file_path))
+        .map_err(|_| StatusCode::GATEWAY_TIMEOUT)?
+        .into_iter()
+        .collect();
+    // Documents may have any name, so format is detected by content
+    let format = try_get_result!(ImageFormat::detect(&bytes), "Unsupported format. Skipping");
     let image = Image::new(
-        bytes.into_iter().collect(),
-        ImageMetadata::new(format!("{}.{}", file_id, ext), user.id, message_id),
+        bytes,
+        ImageMetadata::new(format!("{}.{}", file_id, format.extension()), user.id, message_id),
     );
 
-    let metadata = {
-        let db = {
-            let mut lock = dbs.lock().unwrap();
-            lock.entry(chat_id)
-                .or_insert_with(|| {
-                    let path: PathBuf = STORAGE_DIR_NAME.into();
-                    let path = path.join(chat_id.to_string());
-                    std::fs::create_dir_all(&path).unwrap();
-                    let storage = FileStorage::<ImageMetadata>::new(path);
-                    let db = ImageDb::new(storage);
-                    let db = Arc::new(Mutex::new(db));
-                    db
-                })
-                .clone()
+    if is_check {
+        let (result, threshold) = {
+            let db = get_or_create_db(&dbs, chat_id, DbKind::Images, &db_config).map_err(|e| log_db_error(chat_id, e))?;
+            let db = db.read().unwrap();
+            (db.find_similar(&image, MAX_REPORTED_COPIES), db.threshold())
+        };
+        let reply = match result {
+            MatchResult::AlreadyExists(similar) => {
+                let text = match similar[0].tier {
+                    MatchTier::Identical => "Точно такая же картинка уже была.".to_string(),
+                    _ if similar[0].feature_matches.is_some() => {
+                        "Такая картинка уже была, это её обрезанная копия или часть.".to_string()
+                    }
+                    MatchTier::ReEncoded => format!(
+                        "Такая картинка уже была, схожесть {:.0}%.",
+                        get_similarity_percent(similar[0].distance, threshold)
+                    ),
+                    MatchTier::SameTemplate => "Такой шаблон уже был, но с другим текстом.".to_string(),
+                };
+                match format_message_links(chat_id, &similar) {
+                    Some(links) => format!("{} Копии: {}.", text, links),
+                    None => text,
+                }
+            }
+            MatchResult::New => "Такой картинки ещё не было.".to_string(),
+            MatchResult::Undecodable => "Не получилось прочитать эту картинку.".to_string(),
+            MatchResult::Rejected(_) => "Картинка слишком большая, проверять её не буду.".to_string(),
         };
-        let mut db = db.lock().unwrap();
+        await!(telegram_client.send_message(chat_id, &reply, Some(message_id))).map_err(|e| {
+            error!("Unknown exception while sending request: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(());
+    }
 
-        match db.save_image_if_new(image) {
-            ImageVariant::AlreadyExists(metadata) => metadata,
-            ImageVariant::New => {
+    let (similar, threshold) = {
+        let db = get_or_create_db(&dbs, chat_id, DbKind::Images, &db_config).map_err(|e| log_db_error(chat_id, e))?;
+        let mut db = db.write().unwrap();
+
+        let result = db
+            .save_image_if_new_ranked(image, MAX_REPORTED_COPIES)
+            .map_err(|e| log_db_error(chat_id, e))?;
+        match result {
+            MatchResult::AlreadyExists(similar) => (similar, db.threshold()),
+            MatchResult::New => {
                 info!("New image! Congrats, user {}", user.first_name);
                 return Ok(());
             }
+            MatchResult::Undecodable => {
+                warn!("File {} could not be decoded. Skipping", file_id);
+                return Ok(());
+            }
+            MatchResult::Rejected(rejection) => {
+                warn!("File {} was rejected: {:?}. Skipping", file_id, rejection);
+                return Ok(());
+            }
         }
     };
+    // Known template with new text is a new meme, not a boyan
+    let similar: Vec<_> = similar
+        .into_iter()
+        .filter(|x| x.tier != MatchTier::SameTemplate)
+        .collect();
+    if similar.is_empty() {
+        info!("New text on a known template, user {}", user.first_name);
+        return Ok(());
+    }
+    let metadata = &similar[0].metadata;
 
     let details = user
         .username
@@ -226,6 +618,32 @@ async fn handle_request_internal(
         "Похоже, что [{}{}](tg://user?id={}) боян добавил.",
         &user.first_name, &details, &user.id
     );
+    let text = if similar[0].tier == MatchTier::Identical {
+        format!("{} Это точная копия.", text)
+    } else if similar[0].feature_matches.is_some() {
+        // Confirmed matches are too far by hash, so similarity percent would say nothing
+        format!("{} Это обрезанная копия или часть оригинала.", text)
+    } else {
+        format!(
+            "{} Схожесть с оригиналом {:.0}%.",
+            text,
+            get_similarity_percent(similar[0].distance, threshold)
+        )
+    };
+    let text = if similar[0].transform == Transform::Identity {
+        text
+    } else {
+        format!("{} Картинку перевернули или отразили.", text)
+    };
+    let text = match similar[0].tile {
+        Some(TileMatch::Query(_)) => format!("{} Боян нашёлся в коллаже.", text),
+        Some(TileMatch::Stored(_)) => format!("{} Картинку вырезали из коллажа.", text),
+        None => text,
+    };
+    let text = match format_message_links(chat_id, &similar[1..]) {
+        Some(links) => format!("{} Другие копии: {}.", text, links),
+        None => text,
+    };
 
     let send_message = telegram_client.send_message(
         chat_id,
@@ -251,14 +669,223 @@ async fn handle_request_internal(
     Ok(())
 }
 
-fn get_file_path_if_processable(file_path: Option<String>) -> Option<(String, String)> {
-    if let Some(file_path) = file_path {
-        if let Some(ext) = file_path.rsplit('.').next().map(|x| x.to_string()) {
-            let ext = ext.to_lowercase();
-            if ext == "png" || ext == "jpg" || ext == "jpeg" {
-                return Some((file_path, ext));
+fn get_similarity_percent(distance: f64, threshold: f64) -> f64 {
+    (100.0 * (1.0 - distance / threshold)).max(0.0).min(100.0)
+}
+
+fn format_message_links(chat_id: i64, similar: &[SimilarImage<ImageMetadata>]) -> Option<String> {
+    let links = similar
+        .iter()
+        .filter_map(|x| get_message_link(chat_id, x.metadata.message_id))
+        .enumerate()
+        .map(|(i, link)| format!("[{}]({})", i + 1, link))
+        .collect::<Vec<_>>();
+    if links.is_empty() {
+        None
+    } else {
+        Some(links.join(", "))
+    }
+}
+
+/// Only supergroup messages could be linked, their ids look like `-100<channel id>`
+fn get_message_link(chat_id: i64, message_id: i64) -> Option<String> {
+    let chat_id = chat_id.to_string();
+    if chat_id.starts_with("-100") {
+        Some(format!("https://t.me/c/{}/{}", &chat_id[4..], message_id))
+    } else {
+        None
+    }
+}
+
+/// Documents of other types are never images, so they are not downloaded. Unknown types are checked by content
+fn may_be_media(document: &Document) -> bool {
+    match document.mime_type {
+        Some(ref mime_type) => {
+            let is_media = mime_type.starts_with("image/") || mime_type.starts_with("video/");
+            is_media || mime_type == "application/octet-stream"
+        }
+        None => true,
+    }
+}
+
+/// Telegram reports file size before download, so files over the limit are not downloaded at all
+fn is_too_large(file: &File, limits: &ImageLimits) -> bool {
+    file.file_size.map_or(false, |size| size as u64 > limits.max_bytes as u64)
+}
+
+fn get_or_create_db(
+    dbs: &SyncedDbMap,
+    chat_id: i64,
+    kind: DbKind,
+    db_config: &DbConfig,
+) -> Result<SyncedDb, ImageDbError> {
+    let slot = dbs.lock().unwrap().entry((chat_id, kind)).or_default().clone();
+    let mut loaded = slot.lock().unwrap();
+    if let Some(ref db) = *loaded {
+        return Ok(db.clone());
+    }
+    let path = match kind {
+        DbKind::Images => get_chat_path(chat_id),
+        DbKind::Stickers => get_chat_path(chat_id).join(STICKERS_DIR_NAME),
+    };
+    std::fs::create_dir_all(&path)?;
+    let settings = ChatSettings::load(chat_id);
+    let storage = FileStorage::<ImageMetadata>::with_mode(path, db_config.storage_mode);
+    let hasher = db_config.create_hasher();
+    let mut db = match kind {
+        DbKind::Images => {
+            let threshold = settings.threshold.unwrap_or_else(|| hasher.default_threshold());
+            let preprocessing = db_config.preprocessing;
+            let mut db = ImageDb::with_limits(storage, hasher, threshold, preprocessing, db_config.limits, |x| {
+                info!("Loaded {} of {} images of chat {}", x.processed, x.total, chat_id)
+            })?;
+            db.set_retention(db_config.retention);
+            db.set_transform_invariant(db_config.transform_invariant);
+            db.set_verification(db_config.verification);
+            db
+        }
+        DbKind::Stickers => {
+            let threshold = settings.sticker_threshold.unwrap_or_else(|| hasher.default_threshold());
+            let preprocessing = Preprocessing::default();
+            let mut db = ImageDb::with_limits(storage, hasher, threshold, preprocessing, db_config.limits, |_| {})?;
+            db.set_retention(Some(STICKER_RETENTION));
+            db
+        }
+    };
+    db.set_capacity(db_config.capacity);
+    db.set_eviction_policy(db_config.eviction_policy);
+    db.evict_expired()?;
+    db.evict_over_capacity()?;
+    let db = Arc::new(RwLock::new(db));
+    *loaded = Some(db.clone());
+    Ok(db)
+}
+
+/// Everyone is an administrator of a private chat
+fn is_chat_admin(telegram_client: &TelegramClient, chat: &Chat, user_id: i64) -> impl Future<Item = bool, Error = ()> {
+    if chat.chat_type == "private" {
+        return Either::A(future::ok::<bool, ()>(true));
+    }
+    let member = telegram_client.get_chat_member(chat.id, user_id);
+    Either::B(member.then(|x| {
+        let is_admin = x.map(|x| x.status == "creator" || x.status == "administrator").unwrap_or(false);
+        Ok::<bool, ()>(is_admin)
+    }))
+}
+
+fn log_db_error(chat_id: i64, e: ImageDbError) -> StatusCode {
+    error!("Image database of chat {} failed: {:?}", chat_id, e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn get_chat_path(chat_id: i64) -> PathBuf {
+    let path: PathBuf = STORAGE_DIR_NAME.into();
+    path.join(chat_id.to_string())
+}
+
+/// Settings live next to chat directory, so storage never mistakes them for image metadata
+fn get_settings_path(chat_id: i64) -> PathBuf {
+    get_chat_path(chat_id).with_extension(SETTINGS_EXTENSION)
+}
+
+/// Returns command name without bot mention if `text` starts with a command
+fn get_command(text: &str) -> Option<&str> {
+    let command = text.split_whitespace().next()?;
+    if command.starts_with('/') {
+        command.split('@').next()
+    } else {
+        None
+    }
+}
+
+/// Whether `text` is a command that changes chat settings rather than shows them
+fn changes_settings(text: &str) -> bool {
+    match get_command(text) {
+        Some("/threshold") | Some("/stickers") | Some("/sticker_threshold") => text.split_whitespace().nth(1).is_some(),
+        _ => false,
+    }
+}
+
+/// Executes bot command if `text` is one, returns text of the reply.
+/// Commands that change settings are only executed for chat administrators
+fn handle_command(text: &str, chat_id: i64, is_admin: bool, dbs: &SyncedDbMap, db_config: &DbConfig) -> Option<String> {
+    let command = get_command(text)?;
+    let mut args = text.split_whitespace().skip(1);
+    if changes_settings(text) && !is_admin {
+        return Some("Менять настройки могут только администраторы чата".to_string());
+    }
+    match command {
+        "/threshold" => {
+            let db = match get_or_create_db(dbs, chat_id, DbKind::Images, db_config) {
+                Ok(db) => db,
+                Err(e) => {
+                    log_db_error(chat_id, e);
+                    return Some("Не удалось загрузить базу картинок этого чата".to_string());
+                }
+            };
+            let mut db = db.write().unwrap();
+            let reply = match args.next().map(|x| x.parse::<f64>()) {
+                None => format!("Текущий порог схожести: {}", db.threshold()),
+                Some(Ok(threshold)) if threshold >= 0.0 => {
+                    db.set_threshold(threshold);
+                    let mut settings = ChatSettings::load(chat_id);
+                    settings.threshold = Some(threshold);
+                    if let Err(e) = settings.save(chat_id) {
+                        error!("Failed to save settings of chat {}: {:?}", chat_id, e);
+                    }
+                    format!("Порог схожести установлен: {}", threshold)
+                }
+                _ => "Порог схожести должен быть неотрицательным числом".to_string(),
+            };
+            Some(reply)
+        }
+        "/stickers" => {
+            let mut settings = ChatSettings::load(chat_id);
+            let reply = match args.next() {
+                None if settings.stickers => "Повторы стикеров отслеживаются",
+                None => "Повторы стикеров не отслеживаются",
+                Some("on") => {
+                    settings.stickers = true;
+                    "Повторы стикеров теперь отслеживаются"
+                }
+                Some("off") => {
+                    settings.stickers = false;
+                    // Sticker database is not needed anymore, it is loaded again when stickers are enabled
+                    dbs.lock().unwrap().remove(&(chat_id, DbKind::Stickers));
+                    "Повторы стикеров больше не отслеживаются"
+                }
+                Some(_) => return Some("Используйте /stickers on или /stickers off".to_string()),
+            };
+            if let Err(e) = settings.save(chat_id) {
+                error!("Failed to save settings of chat {}: {:?}", chat_id, e);
             }
+            Some(reply.to_string())
+        }
+        "/sticker_threshold" => {
+            let db = match get_or_create_db(dbs, chat_id, DbKind::Stickers, db_config) {
+                Ok(db) => db,
+                Err(e) => {
+                    log_db_error(chat_id, e);
+                    return Some("Не удалось загрузить базу стикеров этого чата".to_string());
+                }
+            };
+            let mut db = db.write().unwrap();
+            let reply = match args.next().map(|x| x.parse::<f64>()) {
+                None => format!("Текущий порог схожести стикеров: {}", db.threshold()),
+                Some(Ok(threshold)) if threshold >= 0.0 => {
+                    db.set_threshold(threshold);
+                    let mut settings = ChatSettings::load(chat_id);
+                    settings.sticker_threshold = Some(threshold);
+                    if let Err(e) = settings.save(chat_id) {
+                        error!("Failed to save settings of chat {}: {:?}", chat_id, e);
+                    }
+                    format!("Порог схожести стикеров установлен: {}", threshold)
+                }
+                _ => "Порог схожести должен быть неотрицательным числом".to_string(),
+            };
+            Some(reply)
         }
+        _ => None,
     }
-    None
 }
+
@@ -72,6 +72,15 @@ impl TelegramClient {
         self.send_and_deserialize(Method::GET, &url, Body::empty())
     }
 
+    pub fn get_chat_member(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+    ) -> impl Future<Item = ChatMember, Error = TelegramClientError> {
+        let url = format!("bot{}/getChatMember?chat_id={}&user_id={}", self.token, chat_id, user_id);
+        self.send_and_deserialize(Method::GET, &url, Body::empty())
+    }
+
     pub fn download_file(&self, file_path: &str) -> impl Future<Item = Bytes, Error = TelegramClientError> {
         let url = format!("file/bot{}/{}", self.token, file_path);
         self.send(Method::GET, &url, Body::empty(), |chunk| Ok(chunk.into_bytes()))